@@ -0,0 +1,631 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use futures::future::{self, Future};
+use glob::Pattern;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use boxfuture::{BoxFuture, Boxable};
+
+use super::{
+  Conjunction, Dir, GlobParsedSource, IgnoreStack, Link, PathGlob, PathGlobs, PathStat, Stat, VFS,
+};
+
+/// The most distinct sibling-wildcard-sets `GLOB_SET_CACHE` will hold onto at once. Pants runs
+/// this code inside a long-lived `pantsd` daemon across many builds with many different glob
+/// specs, so an unbounded cache here would be a slow memory leak rather than a one-shot-process
+/// cache; eviction is FIFO (oldest-inserted first) rather than true LRU, which is a cheaper bound
+/// to maintain and good enough given re-compiling an evicted-then-reused `GlobSet` just costs one
+/// more `GlobSetBuilder::build` call.
+const GLOB_SET_CACHE_CAPACITY: usize = 4096;
+
+/// A `GlobSet` cache bounded to `GLOB_SET_CACHE_CAPACITY` entries: once full, inserting a new
+/// pattern set evicts the oldest one still cached.
+struct GlobSetCache {
+  sets: HashMap<Vec<String>, Arc<GlobSet>>,
+  insertion_order: VecDeque<Vec<String>>,
+}
+
+impl GlobSetCache {
+  fn new() -> GlobSetCache {
+    GlobSetCache {
+      sets: HashMap::new(),
+      insertion_order: VecDeque::new(),
+    }
+  }
+
+  fn get(&self, key: &[String]) -> Option<Arc<GlobSet>> {
+    self.sets.get(key).cloned()
+  }
+
+  fn insert(&mut self, key: Vec<String>, value: Arc<GlobSet>) {
+    if self.sets.len() >= GLOB_SET_CACHE_CAPACITY {
+      if let Some(oldest) = self.insertion_order.pop_front() {
+        self.sets.remove(&oldest);
+      }
+    }
+    self.insertion_order.push_back(key.clone());
+    self.sets.insert(key, value);
+  }
+}
+
+lazy_static! {
+  /// Compiling a `GlobSet` isn't free, and the same set of sibling wildcards (e.g. the remainder
+  /// of a `**` expansion shared by every matching subdirectory) tends to recur many times over the
+  /// course of a single walk, so we keep compiled sets around keyed by the literal patterns they
+  /// were built from.
+  static ref GLOB_SET_CACHE: Mutex<GlobSetCache> = Mutex::new(GlobSetCache::new());
+}
+
+///
+/// Compiles `wildcards` into a single `GlobSet` that classifies a directory entry's file name in
+/// one pass, rather than testing it against each `Pattern` in turn: `GlobSet::matches` returns the
+/// indexes of every wildcard (in `wildcards` order) that the candidate satisfies, which lets a
+/// caller bucket one directory listing across however many sibling patterns apply to it without
+/// the cost scaling with (entries × patterns).
+///
+fn compiled_glob_set(wildcards: &[Pattern]) -> Result<Arc<GlobSet>, String> {
+  let key: Vec<String> = wildcards.iter().map(|w| w.as_str().to_string()).collect();
+  if let Some(cached) = GLOB_SET_CACHE.lock().unwrap().get(&key) {
+    return Ok(cached.clone());
+  }
+
+  let mut builder = GlobSetBuilder::new();
+  for wildcard in wildcards {
+    let glob = Glob::new(wildcard.as_str())
+      .map_err(|e| format!("Could not compile {:?} as a glob: {:?}", wildcard, e))?;
+    builder.add(glob);
+  }
+  let compiled = Arc::new(
+    builder
+      .build()
+      .map_err(|e| format!("Could not build a glob matcher for {:?}: {:?}", key, e))?,
+  );
+
+  GLOB_SET_CACHE
+    .lock()
+    .unwrap()
+    .insert(key, compiled.clone());
+  Ok(compiled)
+}
+
+///
+/// A context for expanding `PathGlobs` into `PathStat`s, given some way to list a `Dir`'s
+/// contents and resolve `Link`s (a `VFS`). Kept separate from `VFS` itself because `VFS` is the
+/// narrow "how do I talk to the filesystem" interface that both `PosixFS` and test doubles
+/// implement, while everything in here is pure glob-walking logic built on top of it.
+///
+pub trait GlobMatching<E: Send + Sync + 'static>: VFS<E> {
+  ///
+  /// Canonicalize the `Link` at `symbolic_path`, returning `None` if it (transitively) points
+  /// nowhere.
+  ///
+  fn canonicalize(&self, symbolic_path: PathBuf, link: &Link) -> BoxFuture<Option<PathStat>, E> {
+    self.canonicalize_link(symbolic_path, link.clone(), HashSet::new(), 0)
+  }
+
+  ///
+  /// Does the work of `canonicalize`, additionally threading through `visited` (the canonical
+  /// paths of every `Link` already dereferenced on this chain) and `depth` (how many hops we've
+  /// followed so far), so that a cycle (`a` -> `b` -> `a`) or a pathologically long chain yields a
+  /// well-formed error via `VFS::mk_error` instead of recursing until the stack is exhausted.
+  /// Since `VFS` doesn't expose a way to stat a single arbitrary path, we find the destination's
+  /// `Stat` by listing its parent directory, which also means a link pointing at another link is
+  /// resolved by recursing back into this method.
+  ///
+  fn canonicalize_link(
+    &self,
+    symbolic_path: PathBuf,
+    link: Link,
+    mut visited: HashSet<PathBuf>,
+    depth: usize,
+  ) -> BoxFuture<Option<PathStat>, E> {
+    let max_link_depth = self.max_link_depth();
+    if depth >= max_link_depth {
+      return future::err(Self::mk_error(&format!(
+        "Maximum link depth of {} exceeded while expanding symlink {:?}",
+        max_link_depth, link
+      )))
+      .to_boxed();
+    }
+    if !visited.insert(link.0.clone()) {
+      return future::err(Self::mk_error(&format!(
+        "Symlink cycle detected: {:?} was already visited while expanding symlink chain",
+        link.0
+      )))
+      .to_boxed();
+    }
+
+    let vfs = self.clone();
+    self
+      .read_link(&link)
+      .and_then(move |dest_path| {
+        let dest_parent = dest_path
+          .parent()
+          .map(|p| p.to_owned())
+          .unwrap_or_else(PathBuf::new);
+        let dest_file_name = match dest_path.file_name() {
+          Some(f) => f.to_owned(),
+          None => return future::ok(None).to_boxed(),
+        };
+        vfs
+          .scandir(Dir(dest_parent))
+          .then(move |listing_res| match listing_res {
+            Ok(listing) => {
+              match listing
+                .0
+                .iter()
+                .find(|stat| stat.path().file_name() == Some(dest_file_name.as_os_str()))
+              {
+                Some(&Stat::Dir(ref d)) => {
+                  future::ok(Some(PathStat::dir(symbolic_path, d.clone()))).to_boxed()
+                }
+                Some(&Stat::File(ref f)) => {
+                  future::ok(Some(PathStat::file(symbolic_path, f.clone()))).to_boxed()
+                }
+                Some(&Stat::Link(ref l)) => {
+                  vfs.canonicalize_link(symbolic_path, l.clone(), visited, depth + 1)
+                }
+                None => future::ok(None).to_boxed(),
+              }
+            }
+            // The parent of the link's destination doesn't exist (or isn't a directory): the
+            // link is dangling.
+            Err(_) => future::ok(None).to_boxed(),
+          })
+          .to_boxed()
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Discovers any `.gitignore`/`.ignore` living directly in `dir` and, if either exists, layers
+  /// it onto `ignore_stack`. Split out from `directory_listing` so the extended stack can be
+  /// handed both to the listing's own entry filtering and to whatever recursion happens into
+  /// this directory's children.
+  ///
+  fn push_ignore_stack(
+    &self,
+    dir: &Dir,
+    ignore_stack: &Arc<IgnoreStack>,
+  ) -> BoxFuture<Arc<IgnoreStack>, E> {
+    let ignore_stack = ignore_stack.clone();
+    self
+      .discover_ignore(dir)
+      .map(move |discovered| match discovered {
+        Some(discovered_excludes) => IgnoreStack::push(&ignore_stack, discovered_excludes),
+        None => ignore_stack,
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Lists the contents of `canonical_dir` once and classifies every entry's file name against
+  /// all of `wildcards` in a single pass (via a compiled `GlobSet`, rather than testing each entry
+  /// against each `Pattern` in turn), pruning anything `ignore_stack` (extended with whatever this
+  /// directory's own ignore files contribute) considers ignored before it's ever turned into a
+  /// `PathStat`. Returns the extended stack alongside one bucket of matches per wildcard (aligned
+  /// by index -- an entry matching more than one wildcard appears in more than one bucket), so a
+  /// directory `is_ignored` the moment its own `.gitignore` excludes it is never even recursed
+  /// into, and any `!whitelist` entries a deeper ignore file adds keep applying to everything
+  /// below it.
+  ///
+  fn directory_listing(
+    &self,
+    canonical_dir: Dir,
+    symbolic_path: PathBuf,
+    wildcards: &[Pattern],
+    ignore_stack: &Arc<IgnoreStack>,
+  ) -> BoxFuture<(Arc<IgnoreStack>, Vec<Vec<PathStat>>), E> {
+    let glob_set = match compiled_glob_set(wildcards) {
+      Ok(glob_set) => glob_set,
+      Err(e) => return future::err(Self::mk_error(&e)).to_boxed(),
+    };
+    let num_wildcards = wildcards.len();
+    let vfs = self.clone();
+    let vfs2 = self.clone();
+    self
+      .push_ignore_stack(&canonical_dir, ignore_stack)
+      .join(vfs2.scandir(canonical_dir))
+      .and_then(move |(ignore_stack, dir_listing)| {
+        let filter_ignore_stack = ignore_stack.clone();
+        future::join_all(
+          dir_listing
+            .0
+            .iter()
+            .filter(|stat| !filter_ignore_stack.is_ignored(stat))
+            .filter_map(|stat| {
+              let file_name = stat.path().file_name()?;
+              let matched_indexes = glob_set.matches(file_name);
+              if matched_indexes.is_empty() {
+                None
+              } else {
+                Some((matched_indexes, stat, file_name.to_owned()))
+              }
+            })
+            .map(|(matched_indexes, stat, file_name)| {
+              let symbolic_stat_path = symbolic_path.join(&file_name);
+              let path_stat_future: BoxFuture<Option<PathStat>, E> = match stat {
+                &Stat::Link(ref l) => vfs.canonicalize(symbolic_stat_path, l),
+                &Stat::Dir(ref d) => {
+                  future::ok(Some(PathStat::dir(symbolic_stat_path, d.clone()))).to_boxed()
+                }
+                &Stat::File(ref f) => {
+                  future::ok(Some(PathStat::file(symbolic_stat_path, f.clone()))).to_boxed()
+                }
+              };
+              path_stat_future.map(move |maybe_path_stat| (matched_indexes, maybe_path_stat))
+            })
+            .collect::<Vec<_>>(),
+        )
+        .map(move |matched| (ignore_stack, matched))
+      })
+      .map(move |(ignore_stack, matched)| {
+        let mut buckets: Vec<Vec<PathStat>> = vec![Vec::new(); num_wildcards];
+        for (matched_indexes, maybe_path_stat) in matched {
+          if let Some(path_stat) = maybe_path_stat {
+            for idx in matched_indexes {
+              buckets[idx].push(path_stat.clone());
+            }
+          }
+        }
+        (ignore_stack, buckets)
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Recurses the `remainder` of a `DirWildcard` underneath every directory matched by its
+  /// preceding wildcard, using whatever extension of `ignore_stack` applied at the level those
+  /// matches were listed at.
+  ///
+  fn expand_dir_wildcard_matches(
+    &self,
+    dir_matches: Vec<PathStat>,
+    remainder: Vec<Pattern>,
+    ignore_stack: Arc<IgnoreStack>,
+  ) -> BoxFuture<Vec<PathStat>, E> {
+    let vfs = self.clone();
+    future::join_all(
+      dir_matches
+        .into_iter()
+        .filter_map(|path_stat| match path_stat {
+          PathStat::Dir { path, stat } => Some((path, stat)),
+          PathStat::File { .. } => None,
+        })
+        .map(|(symbolic_path, dir)| {
+          let child_globs = match PathGlob::parse_globs(dir, symbolic_path, &remainder) {
+            Ok(globs) => globs,
+            Err(e) => return future::err(Self::mk_error(&e)).to_boxed(),
+          };
+          vfs.expand_multi(child_globs, ignore_stack.clone())
+        })
+        .collect::<Vec<_>>(),
+    )
+    .map(|nested_path_stats| nested_path_stats.into_iter().flatten().collect())
+    .to_boxed()
+  }
+
+  ///
+  /// Expands one group of `PathGlob`s that all share a `(canonical_dir, symbolic_path)`, listing
+  /// that directory exactly once and classifying its entries against every sibling wildcard in a
+  /// single pass, then continuing the structural recursion (for any `DirWildcard`s among them)
+  /// from each wildcard's own bucket of matches.
+  ///
+  fn expand_group(
+    &self,
+    canonical_dir: Dir,
+    symbolic_path: PathBuf,
+    path_globs: Vec<PathGlob>,
+    ignore_stack: Arc<IgnoreStack>,
+  ) -> BoxFuture<Vec<PathStat>, E> {
+    let wildcards: Vec<Pattern> = path_globs.iter().map(|g| g.wildcard().clone()).collect();
+    let vfs = self.clone();
+    self
+      .directory_listing(canonical_dir, symbolic_path, &wildcards, &ignore_stack)
+      .and_then(move |(ignore_stack, buckets)| {
+        future::join_all(
+          path_globs
+            .into_iter()
+            .zip(buckets.into_iter())
+            .map(|(path_glob, matches)| match path_glob {
+              PathGlob::Wildcard { .. } => future::ok(matches).to_boxed(),
+              PathGlob::DirWildcard { remainder, .. } => {
+                vfs.expand_dir_wildcard_matches(matches, remainder, ignore_stack.clone())
+              }
+            })
+            .collect::<Vec<_>>(),
+        )
+      })
+      .map(|nested| nested.into_iter().flatten().collect())
+      .to_boxed()
+  }
+
+  ///
+  /// Expands a batch of `PathGlob`s which all share an `ignore_stack`, flattening the resulting
+  /// `PathStat`s (two different globs can both match the same file; callers dedup). Globs rooted
+  /// at the same directory are grouped so that directory is only ever listed once, regardless of
+  /// how many sibling patterns apply to it.
+  ///
+  fn expand_multi(
+    &self,
+    path_globs: Vec<PathGlob>,
+    ignore_stack: Arc<IgnoreStack>,
+  ) -> BoxFuture<Vec<PathStat>, E> {
+    let vfs = self.clone();
+
+    let mut groups: HashMap<(Dir, PathBuf), Vec<PathGlob>> = HashMap::new();
+    let mut group_order: Vec<(Dir, PathBuf)> = Vec::new();
+    for path_glob in path_globs {
+      let key = (
+        path_glob.canonical_dir().clone(),
+        path_glob.symbolic_path().clone(),
+      );
+      if !groups.contains_key(&key) {
+        group_order.push(key.clone());
+      }
+      groups.entry(key).or_insert_with(Vec::new).push(path_glob);
+    }
+
+    future::join_all(
+      group_order
+        .into_iter()
+        .map(|key| {
+          let globs = groups.remove(&key).unwrap();
+          vfs.expand_group(key.0, key.1, globs, ignore_stack.clone())
+        })
+        .collect::<Vec<_>>(),
+    )
+    .map(|nested| nested.into_iter().flatten().collect())
+    .to_boxed()
+  }
+
+  ///
+  /// Expands a `PathGlobs`, bucketing its `include` entries by their (already-canonical) base
+  /// `Dir` so that entries sharing a base are expanded (and deduped) together rather than each
+  /// independently re-listing the same directories, and seeding the walk's `IgnoreStack` with the
+  /// explicit `exclude` patterns -- per-directory `.gitignore`/`.ignore` files get layered on top
+  /// of that base as the walk actually descends, rather than being flattened in up front.
+  ///
+  fn expand(&self, path_globs: &PathGlobs) -> BoxFuture<Vec<PathStat>, E> {
+    let vfs = self.clone();
+    let ignore_stack = IgnoreStack::base(path_globs.exclude.clone());
+
+    let mut globs_by_base: HashMap<Dir, Vec<(GlobParsedSource, PathGlob)>> = HashMap::new();
+    for entry in &path_globs.include {
+      for path_glob in &entry.globs {
+        globs_by_base
+          .entry(path_glob.canonical_dir().clone())
+          .or_insert_with(Vec::new)
+          .push((entry.input.clone(), path_glob.clone()));
+      }
+    }
+
+    let should_check_matches = path_globs.strict_match_behavior.should_check_glob_matches();
+    let should_throw_on_error = path_globs.strict_match_behavior.should_throw_on_error();
+    let conjunction = match path_globs.conjunction {
+      Conjunction::And => Conjunction::And,
+      Conjunction::Or => Conjunction::Or,
+    };
+    let include_count = path_globs.include.len();
+
+    let matched_by_bucket = future::join_all(
+      globs_by_base
+        .into_iter()
+        .map(|(_base, sourced_globs)| {
+          let (sources, globs): (Vec<GlobParsedSource>, Vec<PathGlob>) =
+            sourced_globs.into_iter().unzip();
+          vfs
+            .expand_multi(globs, ignore_stack.clone())
+            .map(move |path_stats| (sources, path_stats))
+        })
+        .collect::<Vec<_>>(),
+    );
+
+    matched_by_bucket
+      .and_then(move |matched_by_bucket| {
+        let mut path_stats = Vec::new();
+        let mut sources_which_matched: HashMap<GlobParsedSource, bool> = HashMap::new();
+        for (sources, stats) in matched_by_bucket {
+          let any_matched = !stats.is_empty();
+          for source in sources {
+            let did_match = sources_which_matched.entry(source).or_insert(false);
+            *did_match = *did_match || any_matched;
+          }
+          path_stats.extend(stats);
+        }
+
+        if should_check_matches {
+          let unmatched_globs: Vec<String> = sources_which_matched
+            .into_iter()
+            .filter(|&(_, did_match)| !did_match)
+            .map(|(source, _)| source.0)
+            .collect();
+          let all_matched = match conjunction {
+            Conjunction::And => unmatched_globs.is_empty(),
+            Conjunction::Or => unmatched_globs.len() < include_count,
+          };
+          if !all_matched {
+            let msg = format!("Globs did not match: {:?} matched nothing.", unmatched_globs);
+            if should_throw_on_error {
+              return future::err(Self::mk_error(&msg)).to_boxed();
+            }
+            warn!("{}", msg);
+          }
+        }
+
+        path_stats.sort_by(|l: &PathStat, r: &PathStat| l.path().cmp(r.path()));
+        path_stats.dedup_by(|l, r| l.path() == r.path());
+        future::ok(path_stats).to_boxed()
+      })
+      .to_boxed()
+  }
+}
+
+impl<E: Send + Sync + 'static, T: VFS<E>> GlobMatching<E> for T {}
+
+impl PathGlob {
+  fn canonical_dir(&self) -> &Dir {
+    match self {
+      &PathGlob::Wildcard {
+        ref canonical_dir, ..
+      } => canonical_dir,
+      &PathGlob::DirWildcard {
+        ref canonical_dir, ..
+      } => canonical_dir,
+    }
+  }
+
+  fn symbolic_path(&self) -> &PathBuf {
+    match self {
+      &PathGlob::Wildcard {
+        ref symbolic_path, ..
+      } => symbolic_path,
+      &PathGlob::DirWildcard {
+        ref symbolic_path, ..
+      } => symbolic_path,
+    }
+  }
+
+  fn wildcard(&self) -> &Pattern {
+    match self {
+      &PathGlob::Wildcard { ref wildcard, .. } => wildcard,
+      &PathGlob::DirWildcard { ref wildcard, .. } => wildcard,
+    }
+  }
+}
+
+#[cfg(test)]
+mod glob_set_test {
+  use std::sync::Arc;
+
+  use glob::Pattern;
+  use globset::GlobSetBuilder;
+
+  use super::{compiled_glob_set, GlobSetCache, GLOB_SET_CACHE_CAPACITY};
+
+  /// `compiled_glob_set` buckets a directory entry's name against every wildcard in a single
+  /// `GlobSet::matches` call; this checks that bucketing agrees, entry by entry and wildcard by
+  /// wildcard, with just testing each `Pattern` individually the old way.
+  #[test]
+  fn glob_set_matches_same_entries_as_individual_patterns() {
+    let wildcards: Vec<Pattern> = vec!["*.rs", "test_*", "lib.rs"]
+      .into_iter()
+      .map(|w| Pattern::new(w).unwrap())
+      .collect();
+    let glob_set = compiled_glob_set(&wildcards).unwrap();
+
+    for candidate in &["lib.rs", "test_foo", "foo.rs", "unrelated", "test_foo.rs"] {
+      let matched_indexes = glob_set.matches(candidate);
+      let want_indexes: Vec<usize> = wildcards
+        .iter()
+        .enumerate()
+        .filter(|(_, wildcard)| wildcard.matches(candidate))
+        .map(|(index, _)| index)
+        .collect();
+      assert_eq!(
+        matched_indexes, want_indexes,
+        "mismatch for candidate {:?}",
+        candidate
+      );
+    }
+  }
+
+  /// The same wildcard list compiles to the same cached `GlobSet` (by pointer identity) the second
+  /// time around, rather than rebuilding it.
+  #[test]
+  fn compiled_glob_set_reuses_cached_entry() {
+    let wildcards = vec![Pattern::new("*.reused_test_marker").unwrap()];
+    let first = compiled_glob_set(&wildcards).unwrap();
+    let second = compiled_glob_set(&wildcards).unwrap();
+    assert!(Arc::ptr_eq(&first, &second));
+  }
+
+  /// Once `GlobSetCache` is at capacity, inserting one more entry evicts the oldest-inserted one
+  /// rather than growing further -- proving the FIFO bound actually bounds memory instead of just
+  /// being documented.
+  #[test]
+  fn glob_set_cache_fifo_eviction_bounds_size() {
+    let mut cache = GlobSetCache::new();
+    let empty_set = Arc::new(GlobSetBuilder::new().build().unwrap());
+    for i in 0..(GLOB_SET_CACHE_CAPACITY + 1) {
+      cache.insert(vec![format!("key_{}", i)], empty_set.clone());
+    }
+    assert_eq!(cache.sets.len(), GLOB_SET_CACHE_CAPACITY);
+    assert!(cache.get(&[format!("key_{}", 0)]).is_none());
+    assert!(cache
+      .get(&[format!("key_{}", GLOB_SET_CACHE_CAPACITY)])
+      .is_some());
+  }
+}
+
+#[cfg(test)]
+mod max_link_depth_test {
+  use std::path::PathBuf;
+  use std::sync::Arc;
+
+  use futures::future::{self, Future};
+
+  use boxfuture::{BoxFuture, Boxable};
+
+  use super::GlobMatching;
+  use crate::{Dir, DirectoryListing, Link, Stat, VFS};
+
+  /// A `VFS` whose every directory contains nothing but a single link continuing an infinite
+  /// `link_0 -> link_1 -> link_2 -> ...` chain, so the only thing that can ever stop
+  /// `GlobMatching::canonicalize` from recursing forever is `max_link_depth` -- letting this
+  /// override it lets a test exercise the limit with a handful of links instead of 40 real ones.
+  #[derive(Clone)]
+  struct InfiniteLinkChainVfs {
+    max_link_depth: usize,
+  }
+
+  impl VFS<String> for InfiniteLinkChainVfs {
+    fn read_link(&self, link: &Link) -> BoxFuture<PathBuf, String> {
+      let name = link.0.to_string_lossy();
+      let next_index: usize = name
+        .trim_start_matches("link_")
+        .parse::<usize>()
+        .map(|i| i + 1)
+        .unwrap_or(0);
+      future::ok(PathBuf::from(format!("link_{}", next_index))).to_boxed()
+    }
+
+    fn scandir(&self, _dir: Dir) -> BoxFuture<Arc<DirectoryListing>, String> {
+      let stats = (0..(self.max_link_depth + 10))
+        .map(|i| Stat::Link(Link(PathBuf::from(format!("link_{}", i)))))
+        .collect();
+      future::ok(Arc::new(DirectoryListing(stats))).to_boxed()
+    }
+
+    fn is_ignored(&self, _stat: &Stat) -> bool {
+      false
+    }
+
+    fn mk_error(msg: &str) -> String {
+      msg.to_owned()
+    }
+
+    fn max_link_depth(&self) -> usize {
+      self.max_link_depth
+    }
+  }
+
+  #[test]
+  fn overridden_max_link_depth_is_honored() {
+    let vfs = InfiniteLinkChainVfs { max_link_depth: 3 };
+    let error = vfs
+      .canonicalize(PathBuf::from("link_0"), &Link(PathBuf::from("link_0")))
+      .wait()
+      .expect_err("An infinite link chain should exceed even an overridden max_link_depth");
+    assert!(
+      error.contains("Maximum link depth of 3 exceeded"),
+      "Unexpected error message: {}",
+      error
+    );
+  }
+}
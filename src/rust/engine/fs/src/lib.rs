@@ -20,6 +20,9 @@
 
 mod glob_matching;
 pub use glob_matching::GlobMatching;
+mod platform;
+mod watch;
+pub use watch::{Watch, WatchEvent};
 mod snapshot;
 pub use snapshot::{
   OneOffStoreFileByDigest, Snapshot, StoreFileByDigest, EMPTY_DIGEST, EMPTY_FINGERPRINT,
@@ -28,16 +31,21 @@ mod store;
 pub use store::Store;
 mod pool;
 pub use pool::ResettablePool;
+mod walk;
+pub use walk::{walk, DEFAULT_NUM_WALK_WORKERS};
 
 extern crate bazel_protos;
 #[macro_use]
 extern crate boxfuture;
 extern crate byteorder;
 extern crate bytes;
+extern crate crossbeam_deque;
+extern crate crossbeam_utils;
 extern crate digest;
 extern crate futures;
 extern crate futures_cpupool;
 extern crate glob;
+extern crate globset;
 extern crate grpcio;
 extern crate hashing;
 extern crate ignore;
@@ -50,17 +58,17 @@ extern crate lmdb;
 extern crate log;
 #[cfg(test)]
 extern crate mock;
+extern crate notify;
 extern crate protobuf;
 extern crate resettable;
 extern crate sha2;
-#[cfg(test)]
 extern crate tempfile;
 #[cfg(test)]
 extern crate testutil;
 
 use std::cmp::min;
-use std::io::{self, Read};
-use std::os::unix::fs::PermissionsExt;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
 use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 use std::{fmt, fs};
@@ -172,20 +180,162 @@ impl GitignoreStyleExcludes {
     ignore_builder.build()
   }
 
-  fn exclude_patterns(&self) -> &[String] {
+  ///
+  /// Builds a matcher from whichever of `.gitignore`/`.ignore` exist directly inside `dir_abs`,
+  /// returning `None` if neither file is present. Unlike `create`, these patterns come from
+  /// parsing the files themselves (which may contain comments and `!whitelist` negations) rather
+  /// than from a caller-supplied list of literal pattern strings.
+  ///
+  fn discover(dir_abs: &Path) -> Result<Option<Arc<Self>>, String> {
+    let candidates: Vec<PathBuf> = vec![dir_abs.join(".gitignore"), dir_abs.join(".ignore")]
+      .into_iter()
+      .filter(|p| p.is_file())
+      .collect();
+    if candidates.is_empty() {
+      return Ok(None);
+    }
+
+    let mut ignore_builder = GitignoreBuilder::new(dir_abs);
+    for candidate in &candidates {
+      if let Some(e) = ignore_builder.add(candidate) {
+        return Err(format!("Could not parse ignore file {:?}: {:?}", candidate, e));
+      }
+    }
+    let gitignore = ignore_builder
+      .build()
+      .map_err(|e| format!("Could not build ignore matcher from {:?}: {:?}", candidates, e))?;
+    Ok(Some(Arc::new(Self {
+      patterns: vec![],
+      gitignore,
+    })))
+  }
+
+  pub fn exclude_patterns(&self) -> &[String] {
     self.patterns.as_slice()
   }
 
-  fn is_ignored(&self, stat: &Stat) -> bool {
+  fn match_stat(&self, stat: &Stat) -> GitignoreMatch {
     let is_dir = match stat {
       &Stat::Dir(_) => true,
       _ => false,
     };
     match self.gitignore.matched(stat.path(), is_dir) {
-      ignore::Match::None | ignore::Match::Whitelist(_) => false,
-      ignore::Match::Ignore(_) => true,
+      ignore::Match::None => GitignoreMatch::NoMatch,
+      ignore::Match::Whitelist(_) => GitignoreMatch::Whitelist,
+      ignore::Match::Ignore(_) => GitignoreMatch::Ignore,
     }
   }
+
+  fn is_ignored(&self, stat: &Stat) -> bool {
+    self.match_stat(stat) == GitignoreMatch::Ignore
+  }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum GitignoreMatch {
+  Ignore,
+  Whitelist,
+  NoMatch,
+}
+
+///
+/// A stack of `GitignoreStyleExcludes`, one per directory level between the root and wherever a
+/// walk has currently descended to. Unlike a single flattened `Gitignore`, this lets a `.gitignore`
+/// found partway down the tree take precedence over (or `!whitelist` back in) paths its ancestors
+/// excluded, which is how `git` itself resolves nested ignore files.
+///
+#[derive(Debug)]
+pub struct IgnoreStack {
+  parent: Option<Arc<IgnoreStack>>,
+  excludes: Arc<GitignoreStyleExcludes>,
+}
+
+impl IgnoreStack {
+  fn base(excludes: Arc<GitignoreStyleExcludes>) -> Arc<IgnoreStack> {
+    Arc::new(IgnoreStack {
+      parent: None,
+      excludes,
+    })
+  }
+
+  fn push(parent: &Arc<IgnoreStack>, excludes: Arc<GitignoreStyleExcludes>) -> Arc<IgnoreStack> {
+    Arc::new(IgnoreStack {
+      parent: Some(parent.clone()),
+      excludes,
+    })
+  }
+
+  ///
+  /// Consults this level's excludes first, and only falls back to the parent level if this
+  /// level's matcher didn't reach a decisive (Ignore or Whitelist) verdict -- so a deeper
+  /// `!whitelist` entry always wins over a shallower `Ignore`, and a deeper `Ignore` is never
+  /// second-guessed by an ancestor.
+  ///
+  pub fn is_ignored(&self, stat: &Stat) -> bool {
+    match self.excludes.match_stat(stat) {
+      GitignoreMatch::Ignore => true,
+      GitignoreMatch::Whitelist => false,
+      GitignoreMatch::NoMatch => self
+        .parent
+        .as_ref()
+        .map(|parent| parent.is_ignored(stat))
+        .unwrap_or(false),
+    }
+  }
+}
+
+#[cfg(test)]
+mod ignore_stack_test {
+  use std::path::PathBuf;
+
+  use super::{File, GitignoreStyleExcludes, IgnoreStack, Stat};
+
+  fn file_stat(name: &str) -> Stat {
+    Stat::File(File {
+      path: PathBuf::from(name),
+      is_executable: false,
+    })
+  }
+
+  /// A deeper level's exclude applies even when no ancestor level excludes anything on its own --
+  /// this is what lets a `.gitignore` found partway down a tree exclude paths a shallower walk
+  /// would otherwise have included.
+  #[test]
+  fn deeper_exclude_applies_even_when_ancestor_does_not_exclude() {
+    let base = IgnoreStack::base(GitignoreStyleExcludes::create(&[]).unwrap());
+    assert!(!base.is_ignored(&file_stat("secret")));
+
+    let deeper_excludes = GitignoreStyleExcludes::create(&["secret".to_owned()]).unwrap();
+    let stack = IgnoreStack::push(&base, deeper_excludes);
+    assert!(stack.is_ignored(&file_stat("secret")));
+  }
+
+  /// A deeper `!whitelist` entry re-includes a path an ancestor level's blanket exclude had
+  /// excluded, matching how `git` resolves nested `.gitignore` files: the most specific (deepest)
+  /// decisive match wins, rather than the first or the most general one.
+  #[test]
+  fn deeper_whitelist_overrides_ancestor_exclude() {
+    let ancestor_excludes = GitignoreStyleExcludes::create(&["secret".to_owned()]).unwrap();
+    let base = IgnoreStack::base(ancestor_excludes);
+    assert!(base.is_ignored(&file_stat("secret")));
+
+    let deeper_excludes = GitignoreStyleExcludes::create(&["!secret".to_owned()]).unwrap();
+    let stack = IgnoreStack::push(&base, deeper_excludes);
+    assert!(!stack.is_ignored(&file_stat("secret")));
+  }
+
+  /// A deeper level that doesn't even mention a path falls through to the ancestor's verdict, so
+  /// an ancestor's exclude still reaches a grandchild directory that contributes no ignore file of
+  /// its own.
+  #[test]
+  fn deeper_level_with_no_opinion_falls_back_to_ancestor() {
+    let ancestor_excludes = GitignoreStyleExcludes::create(&["secret".to_owned()]).unwrap();
+    let base = IgnoreStack::base(ancestor_excludes);
+
+    let deeper_excludes = GitignoreStyleExcludes::create(&["unrelated".to_owned()]).unwrap();
+    let stack = IgnoreStack::push(&base, deeper_excludes);
+    assert!(stack.is_ignored(&file_stat("secret")));
+  }
 }
 
 lazy_static! {
@@ -218,6 +368,12 @@ pub enum PathGlob {
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct GlobParsedSource(String);
 
+impl GlobParsedSource {
+  pub fn as_str(&self) -> &str {
+    &self.0
+  }
+}
+
 #[derive(Clone, Debug)]
 pub struct PathGlobIncludeEntry {
   pub input: GlobParsedSource,
@@ -458,6 +614,63 @@ impl Conjunction {
   }
 }
 
+///
+/// A registry of named file-type aliases (e.g. `rust` -> `*.rs`), mirroring the table the `ignore`
+/// crate ships for its `-t`/`-T` type selection. `PathGlobs::create_with_types` expands selected
+/// type names into ordinary filespec strings at construction time, so the rest of the glob-matching
+/// machinery never needs to know types exist.
+///
+#[derive(Clone, Debug)]
+pub struct FileTypes {
+  types: HashMap<String, Vec<String>>,
+}
+
+impl FileTypes {
+  ///
+  /// A reasonable default table covering the languages this repo itself builds in. Callers with
+  /// more exotic needs can layer additional aliases on with `register`.
+  ///
+  pub fn new() -> Self {
+    let mut types = HashMap::new();
+    types.insert("rust".to_string(), vec!["*.rs".to_string()]);
+    types.insert(
+      "python".to_string(),
+      vec!["*.py".to_string(), "*.pyi".to_string()],
+    );
+    types.insert("java".to_string(), vec!["*.java".to_string()]);
+    types.insert("scala".to_string(), vec!["*.scala".to_string()]);
+    types.insert("go".to_string(), vec!["*.go".to_string()]);
+    types.insert("proto".to_string(), vec!["*.proto".to_string()]);
+    types.insert("thrift".to_string(), vec!["*.thrift".to_string()]);
+    FileTypes { types }
+  }
+
+  /// Registers (or overwrites) a named alias for `patterns`, which are resolved the same way as
+  /// any other glob `Pattern` during matching.
+  pub fn register(&mut self, name: &str, patterns: &[&str]) {
+    self
+      .types
+      .insert(name.to_string(), patterns.iter().map(|p| p.to_string()).collect());
+  }
+
+  fn patterns_for(&self, names: &[String]) -> Result<Vec<String>, String> {
+    let mut patterns = Vec::new();
+    for name in names {
+      match self.types.get(name) {
+        Some(type_patterns) => patterns.extend(type_patterns.iter().cloned()),
+        None => return Err(format!("Unrecognized file type alias: {:?}", name)),
+      }
+    }
+    Ok(patterns)
+  }
+}
+
+impl Default for FileTypes {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
 #[derive(Debug)]
 pub struct PathGlobs {
   include: Vec<PathGlobIncludeEntry>,
@@ -477,6 +690,49 @@ impl PathGlobs {
     Self::create_with_globs_and_match_behavior(include, exclude, strict_match_behavior, conjunction)
   }
 
+  ///
+  /// As `create`, but additionally resolves `include_types`/`exclude_types` through `file_types`
+  /// before construction: each pattern of each included type is joined onto every `include`
+  /// filespec (or onto a bare `**` if `include` is empty) as its own filespec, so a type with
+  /// several patterns contributes several filespecs, each of which participates in `conjunction`
+  /// exactly like any hand-written filespec would; each excluded type name's patterns are appended
+  /// directly to `exclude`, where gitignore-style matching already treats them as "exclude anywhere
+  /// in the tree".
+  ///
+  pub fn create_with_types(
+    include: &[String],
+    exclude: &[String],
+    include_types: &[String],
+    exclude_types: &[String],
+    file_types: &FileTypes,
+    strict_match_behavior: StrictGlobMatching,
+    conjunction: Conjunction,
+  ) -> Result<PathGlobs, String> {
+    let include = if include_types.is_empty() {
+      include.to_vec()
+    } else {
+      let type_patterns = file_types.patterns_for(include_types)?;
+      let bases: Vec<String> = if include.is_empty() {
+        vec![DOUBLE_STAR.to_string()]
+      } else {
+        include.to_vec()
+      };
+      bases
+        .iter()
+        .flat_map(|base| {
+          type_patterns
+            .iter()
+            .map(move |type_pattern| format!("{}/{}", base, type_pattern))
+        })
+        .collect()
+    };
+
+    let mut exclude = exclude.to_vec();
+    exclude.extend(file_types.patterns_for(exclude_types)?);
+
+    Self::create(&include, &exclude, strict_match_behavior, conjunction)
+  }
+
   fn create_with_globs_and_match_behavior(
     include: Vec<PathGlobIncludeEntry>,
     exclude: &[String],
@@ -492,6 +748,22 @@ impl PathGlobs {
     })
   }
 
+  pub fn include(&self) -> &[PathGlobIncludeEntry] {
+    &self.include
+  }
+
+  pub fn exclude(&self) -> &Arc<GitignoreStyleExcludes> {
+    &self.exclude
+  }
+
+  pub fn strict_match_behavior(&self) -> &StrictGlobMatching {
+    &self.strict_match_behavior
+  }
+
+  pub fn conjunction(&self) -> &Conjunction {
+    &self.conjunction
+  }
+
   pub fn from_globs(include: Vec<PathGlob>) -> Result<PathGlobs, String> {
     let include = include
       .into_iter()
@@ -510,6 +782,170 @@ impl PathGlobs {
   }
 }
 
+#[cfg(test)]
+mod path_globs_test {
+  use super::{Conjunction, FileTypes, PathGlobs, StrictGlobMatching};
+
+  fn include_specs(globs: &PathGlobs) -> Vec<String> {
+    globs
+      .include()
+      .iter()
+      .map(|entry| entry.input.as_str().to_owned())
+      .collect()
+  }
+
+  #[test]
+  fn file_types_patterns_for_known_and_unknown() {
+    let mut file_types = FileTypes::new();
+    file_types.register("proto3", &["*.proto3"]);
+    assert_eq!(
+      file_types.patterns_for(&["rust".to_owned()]).unwrap(),
+      vec!["*.rs".to_owned()]
+    );
+    assert_eq!(
+      file_types.patterns_for(&["proto3".to_owned()]).unwrap(),
+      vec!["*.proto3".to_owned()]
+    );
+    assert!(file_types
+      .patterns_for(&["nonexistent".to_owned()])
+      .is_err());
+  }
+
+  /// With no `include` filespecs of its own, `create_with_types` treats the include side as
+  /// "everywhere in the tree", matching a bare `**` prefix.
+  #[test]
+  fn create_with_types_defaults_include_to_double_star_prefix() {
+    let file_types = FileTypes::new();
+    let globs = PathGlobs::create_with_types(
+      &[],
+      &[],
+      &["rust".to_owned()],
+      &[],
+      &file_types,
+      StrictGlobMatching::Ignore,
+      Conjunction::And,
+    )
+    .unwrap();
+    assert_eq!(include_specs(&globs), vec!["**/*.rs".to_owned()]);
+  }
+
+  /// An `include` filespec of its own acts as a prefix each type's pattern is joined onto, rather
+  /// than being replaced outright by the type selection.
+  #[test]
+  fn create_with_types_joins_include_types_onto_existing_include_as_prefix() {
+    let file_types = FileTypes::new();
+    let globs = PathGlobs::create_with_types(
+      &["src/rust".to_owned()],
+      &[],
+      &["rust".to_owned()],
+      &[],
+      &file_types,
+      StrictGlobMatching::Ignore,
+      Conjunction::And,
+    )
+    .unwrap();
+    assert_eq!(include_specs(&globs), vec!["src/rust/*.rs".to_owned()]);
+  }
+
+  /// `exclude_types` resolves through the same `FileTypes` table and is appended to `exclude`,
+  /// where gitignore-style matching already treats a bare pattern as "exclude anywhere in the
+  /// tree" -- no prefix-joining needed on that side.
+  #[test]
+  fn create_with_types_appends_exclude_types_patterns_to_exclude() {
+    let file_types = FileTypes::new();
+    let globs = PathGlobs::create_with_types(
+      &[],
+      &["target".to_owned()],
+      &[],
+      &["rust".to_owned()],
+      &file_types,
+      StrictGlobMatching::Ignore,
+      Conjunction::And,
+    )
+    .unwrap();
+    assert_eq!(
+      globs.exclude().exclude_patterns(),
+      &["target".to_owned(), "*.rs".to_owned()]
+    );
+  }
+}
+
+///
+/// How a `PreparedPathGlobs`'s filespecs combine when deciding whether a path is selected: every
+/// filespec must match (`AllMatch`), or matching any one of them is enough (`AnyMatch`). This is
+/// the same choice `Conjunction` offers `PathGlobs`, just spelled for callers -- like
+/// `store::SnapshotOps::subset` -- that match against paths directly rather than expanding globs
+/// against a live `Dir`.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GlobExpansionConjunction {
+  AllMatch,
+  AnyMatch,
+}
+
+impl From<GlobExpansionConjunction> for Conjunction {
+  fn from(conjunction: GlobExpansionConjunction) -> Conjunction {
+    match conjunction {
+      GlobExpansionConjunction::AllMatch => Conjunction::And,
+      GlobExpansionConjunction::AnyMatch => Conjunction::Or,
+    }
+  }
+}
+
+///
+/// A set of filespecs, each parsed once into its per-path-component `Pattern`s, in a form that
+/// can be matched directly against `/`-joined path strings. Unlike `PathGlobs`, which expands
+/// against a live `Dir` via `GlobMatching::expand`, preparing a `PreparedPathGlobs` never touches
+/// the filesystem at all -- which is what lets a caller holding only an already-stored `Directory`
+/// tree (and no guarantee the paths it describes still exist on disk anywhere) match paths against
+/// it, the way `store::SnapshotOps::subset` does.
+///
+#[derive(Clone, Debug)]
+pub struct PreparedPathGlobs {
+  filespecs: Vec<Vec<Pattern>>,
+  strict_match_behavior: StrictGlobMatching,
+  conjunction: GlobExpansionConjunction,
+}
+
+impl PreparedPathGlobs {
+  pub fn create(
+    filespecs: Vec<String>,
+    strict_match_behavior: StrictGlobMatching,
+    conjunction: GlobExpansionConjunction,
+  ) -> Result<PreparedPathGlobs, String> {
+    let filespecs = filespecs
+      .iter()
+      .map(|filespec| {
+        filespec
+          .split('/')
+          .map(|part| {
+            Pattern::new(part).map_err(|e| format!("Could not parse {:?} as a glob: {:?}", filespec, e))
+          })
+          .collect::<Result<Vec<Pattern>, String>>()
+      })
+      .collect::<Result<Vec<Vec<Pattern>>, String>>()?;
+    Ok(PreparedPathGlobs {
+      filespecs,
+      strict_match_behavior,
+      conjunction,
+    })
+  }
+
+  /// Each filespec, already split on `/` into per-component `Pattern`s -- the form a caller like
+  /// `store::SnapshotOps::subset` needs to walk a tree level by level, a component at a time.
+  pub fn filespecs(&self) -> &[Vec<Pattern>] {
+    &self.filespecs
+  }
+
+  pub fn strict_match_behavior(&self) -> &StrictGlobMatching {
+    &self.strict_match_behavior
+  }
+
+  pub fn conjunction(&self) -> GlobExpansionConjunction {
+    self.conjunction
+  }
+}
+
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum GlobSource {
   ParsedInput(GlobParsedSource),
@@ -529,6 +965,7 @@ pub struct PosixFS {
   root: Dir,
   pool: Arc<ResettablePool>,
   ignore: Arc<GitignoreStyleExcludes>,
+  respect_ignore_files: bool,
 }
 
 impl PosixFS {
@@ -536,6 +973,20 @@ impl PosixFS {
     root: P,
     pool: Arc<ResettablePool>,
     ignore_patterns: &[String],
+  ) -> Result<PosixFS, String> {
+    Self::new_with_ignore_discovery(root, pool, ignore_patterns, true)
+  }
+
+  ///
+  /// As `new`, but `respect_ignore_files` can be set to `false` (analogous to `git`'s
+  /// `--no-ignore`) to disable discovery of per-directory `.gitignore`/`.ignore` files during
+  /// walks, while still honoring the explicit `ignore_patterns`.
+  ///
+  pub fn new_with_ignore_discovery<P: AsRef<Path>>(
+    root: P,
+    pool: Arc<ResettablePool>,
+    ignore_patterns: &[String],
+    respect_ignore_files: bool,
   ) -> Result<PosixFS, String> {
     let root: &Path = root.as_ref();
     let canonical_root = root
@@ -564,6 +1015,7 @@ impl PosixFS {
       root: canonical_root,
       pool: pool,
       ignore: ignore,
+      respect_ignore_files: respect_ignore_files,
     })
   }
 
@@ -590,6 +1042,26 @@ impl PosixFS {
     self.ignore.is_ignored(stat)
   }
 
+  ///
+  /// Looks for a `.gitignore`/`.ignore` directly inside `dir`, returning the matcher they parse
+  /// to (or `None` if neither exists, or if `respect_ignore_files` is `false`). Callers layer the
+  /// result onto an `IgnoreStack` as they descend, rather than folding it into `self.ignore`,
+  /// since which files apply depends on where in the tree a walk currently is.
+  ///
+  pub fn discover_ignore(&self, dir: &Dir) -> BoxFuture<Option<Arc<GitignoreStyleExcludes>>, io::Error> {
+    if !self.respect_ignore_files {
+      return future::ok(None).to_boxed();
+    }
+    let dir_abs = self.root.0.join(&dir.0);
+    self
+      .pool
+      .spawn_fn(move || {
+        GitignoreStyleExcludes::discover(&dir_abs)
+          .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+      })
+      .to_boxed()
+  }
+
   pub fn read_file(&self, file: &File) -> BoxFuture<FileContent, io::Error> {
     let path = file.path.clone();
     let path_abs = self.root.0.join(&file.path);
@@ -669,7 +1141,8 @@ impl PosixFS {
     if file_type.is_dir() {
       Ok(Stat::Dir(Dir(path_for_stat)))
     } else if file_type.is_file() {
-      let is_executable = get_metadata()?.permissions().mode() & 0o100 == 0o100;
+      let metadata = get_metadata()?;
+      let is_executable = platform::is_executable(&metadata, &path_for_stat);
       Ok(Stat::File(File {
         path: path_for_stat,
         is_executable: is_executable,
@@ -705,6 +1178,150 @@ impl PosixFS {
       .map(DirectoryListing)
       .to_boxed()
   }
+
+  ///
+  /// Creates a single directory at `relative_path`; like POSIX `mkdir`, the parent must already
+  /// exist.
+  ///
+  pub fn create_dir(&self, relative_path: PathBuf) -> BoxFuture<(), io::Error> {
+    let path_abs = self.root.0.join(&relative_path);
+    self
+      .pool
+      .spawn_fn(move || std::fs::create_dir(&path_abs))
+      .to_boxed()
+  }
+
+  ///
+  /// Writes `content` to `relative_path` such that a crash at any point leaves either the old
+  /// contents or the complete new contents at that path, never a partial write: the bytes are
+  /// written to a randomly-named temporary file created alongside the destination (so the
+  /// subsequent rename is guaranteed to stay on the same filesystem), `fsync`ed, and then renamed
+  /// directly over the destination, which POSIX guarantees is atomic. The parent directory is
+  /// `fsync`ed afterward so the rename itself survives a crash, not just the file's data. If
+  /// anything fails before the rename, the temporary file is cleaned up by `NamedTempFile`'s own
+  /// `Drop` impl, so the destination is never left half-written.
+  ///
+  pub fn write_file(
+    &self,
+    relative_path: PathBuf,
+    content: Bytes,
+    is_executable: bool,
+  ) -> BoxFuture<(), io::Error> {
+    let path_abs = self.root.0.join(&relative_path);
+    self
+      .pool
+      .spawn_fn(move || Self::write_file_sync(&path_abs, &content, is_executable))
+      .to_boxed()
+  }
+
+  fn write_file_sync(path_abs: &Path, content: &[u8], is_executable: bool) -> Result<(), io::Error> {
+    let parent = path_abs.parent().ok_or_else(|| {
+      io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Cannot write to a path without a parent: {:?}", path_abs),
+      )
+    })?;
+
+    let mut tmp = tempfile::Builder::new()
+      .prefix(".tmp")
+      .tempfile_in(parent)?;
+    tmp.write_all(content)?;
+    platform::set_executable(tmp.as_file(), is_executable)?;
+    tmp.as_file().sync_all()?;
+    tmp.persist(path_abs).map_err(|e| e.error)?;
+
+    std::fs::File::open(parent)?.sync_all()
+  }
+
+  ///
+  /// Copies the file at `src` (relative to this `PosixFS`'s root) to `dst`, overwriting `dst` if
+  /// it already exists.
+  ///
+  pub fn copy_file(&self, src: PathBuf, dst: PathBuf) -> BoxFuture<(), io::Error> {
+    let src_abs = self.root.0.join(&src);
+    let dst_abs = self.root.0.join(&dst);
+    self
+      .pool
+      .spawn_fn(move || std::fs::copy(&src_abs, &dst_abs).map(|_| ()))
+      .to_boxed()
+  }
+
+  ///
+  /// Renames `src` to `dst`, both relative to this `PosixFS`'s root.
+  ///
+  pub fn rename(&self, src: PathBuf, dst: PathBuf) -> BoxFuture<(), io::Error> {
+    let src_abs = self.root.0.join(&src);
+    let dst_abs = self.root.0.join(&dst);
+    self
+      .pool
+      .spawn_fn(move || std::fs::rename(&src_abs, &dst_abs))
+      .to_boxed()
+  }
+
+  ///
+  /// Removes the file at `relative_path`.
+  ///
+  pub fn remove_file(&self, relative_path: PathBuf) -> BoxFuture<(), io::Error> {
+    let path_abs = self.root.0.join(&relative_path);
+    self
+      .pool
+      .spawn_fn(move || std::fs::remove_file(&path_abs))
+      .to_boxed()
+  }
+
+  ///
+  /// Removes the (empty) directory at `relative_path`.
+  ///
+  pub fn remove_dir(&self, relative_path: PathBuf) -> BoxFuture<(), io::Error> {
+    let path_abs = self.root.0.join(&relative_path);
+    self
+      .pool
+      .spawn_fn(move || std::fs::remove_dir(&path_abs))
+      .to_boxed()
+  }
+
+  ///
+  /// Creates a symlink at `dst` pointing at `src` (both relative to this `PosixFS`'s root),
+  /// via whichever of `platform::create_symlink`'s platform-appropriate syscalls applies.
+  ///
+  pub fn create_symlink(&self, src: PathBuf, dst: PathBuf) -> BoxFuture<(), io::Error> {
+    let src_abs = self.root.0.join(&src);
+    let dst_abs = self.root.0.join(&dst);
+    self
+      .pool
+      .spawn_fn(move || platform::create_symlink(&src_abs, &dst_abs))
+      .to_boxed()
+  }
+
+  ///
+  /// Begins watching this `PosixFS`'s root for changes, returning a `Stream` of `WatchEvent`s that
+  /// callers can use to invalidate cached `stat`/`scandir`/`path_stats` results for the paths it
+  /// reports, rather than rescanning the whole tree on every call.
+  ///
+  pub fn watch(&self) -> Result<Watch, String> {
+    Watch::new(&self.root.0)
+  }
+
+  ///
+  /// The single entry point for ignore-aware, glob-aware, subtree-pruning enumeration of this
+  /// `PosixFS`'s root: builds a `PathGlobs` from `include`/`exclude` filespecs and hands it to
+  /// `GlobMatching::expand`, which layers the glob-matching and `.gitignore`-respecting traversal
+  /// already built on `scandir`/`path_stats` -- callers who already have a `PathGlobs` in hand
+  /// should call `expand` directly instead.
+  ///
+  pub fn expand_globs(
+    fs: Arc<PosixFS>,
+    include: &[String],
+    exclude: &[String],
+    strict_match_behavior: StrictGlobMatching,
+    conjunction: Conjunction,
+  ) -> BoxFuture<Vec<PathStat>, io::Error> {
+    let path_globs = match PathGlobs::create(include, exclude, strict_match_behavior, conjunction) {
+      Ok(path_globs) => path_globs,
+      Err(e) => return future::err(io::Error::new(io::ErrorKind::InvalidInput, e)).to_boxed(),
+    };
+    fs.expand(&path_globs)
+  }
 }
 
 impl VFS<io::Error> for Arc<PosixFS> {
@@ -720,6 +1337,10 @@ impl VFS<io::Error> for Arc<PosixFS> {
     PosixFS::is_ignored(self, stat)
   }
 
+  fn discover_ignore(&self, dir: &Dir) -> BoxFuture<Option<Arc<GitignoreStyleExcludes>>, io::Error> {
+    PosixFS::discover_ignore(self, dir)
+  }
+
   fn mk_error(msg: &str) -> io::Error {
     io::Error::new(io::ErrorKind::Other, msg)
   }
@@ -774,6 +1395,27 @@ pub trait VFS<E: Send + Sync + 'static>: Clone + Send + Sync + 'static {
   fn scandir(&self, dir: Dir) -> BoxFuture<Arc<DirectoryListing>, E>;
   fn is_ignored(&self, stat: &Stat) -> bool;
   fn mk_error(msg: &str) -> E;
+
+  ///
+  /// Discovers any per-directory ignore files (e.g. `.gitignore`) that apply starting at `dir`.
+  /// Implementations which have no notion of VCS-style ignore file discovery can rely on this
+  /// default, which never contributes any additional excludes.
+  ///
+  fn discover_ignore(&self, _dir: &Dir) -> BoxFuture<Option<Arc<GitignoreStyleExcludes>>, E> {
+    future::ok(None).to_boxed()
+  }
+
+  ///
+  /// The longest chain of symlinks `GlobMatching::canonicalize` will follow before giving up on a
+  /// (presumably pathological, since a genuine cycle is caught well before this) chain, in the
+  /// same ballpark as the `ELOOP` limit most platforms enforce (Linux's is 40). Lives on `VFS`
+  /// rather than on `GlobMatching` itself so a test-double `VFS` implementation can override it to
+  /// exercise the limit with a short chain, rather than every caller being stuck with whatever a
+  /// blanket `impl<E, T: VFS<E>> GlobMatching<E> for T` would otherwise hard-code.
+  ///
+  fn max_link_depth(&self) -> usize {
+    40
+  }
 }
 
 pub struct FileContent {
@@ -831,7 +1473,8 @@ mod posixfs_test {
   extern crate testutil;
 
   use super::{
-    Dir, DirectoryListing, File, Link, PathStat, PathStatGetter, PosixFS, ResettablePool, Stat,
+    Bytes, Dir, DirectoryListing, File, Link, PathStat, PathStatGetter, PosixFS, ResettablePool,
+    Stat,
   };
   use futures::Future;
   use std;
@@ -1031,6 +1674,114 @@ mod posixfs_test {
       .expect_err("Want error");
   }
 
+  #[test]
+  fn create_dir_makes_an_empty_directory() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let posix_fs = new_posixfs(&dir.path());
+    let path = PathBuf::from("enclosure");
+    posix_fs.create_dir(path.clone()).wait().unwrap();
+    assert!(dir.path().join(&path).is_dir());
+  }
+
+  #[test]
+  fn create_dir_missing_parent_errors() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let posix_fs = new_posixfs(&dir.path());
+    posix_fs
+      .create_dir(PathBuf::from("no_marmosets_here/enclosure"))
+      .wait()
+      .expect_err("Want error");
+  }
+
+  #[test]
+  fn write_file_writes_content_and_executable_bit() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let posix_fs = new_posixfs(&dir.path());
+    let path = PathBuf::from("marmosets");
+    posix_fs
+      .write_file(path.clone(), Bytes::from("cute"), true)
+      .wait()
+      .unwrap();
+    assert_eq!(
+      std::fs::read(dir.path().join(&path)).unwrap(),
+      b"cute".to_vec()
+    );
+    assert_only_file_is_executable(dir.path(), true);
+  }
+
+  #[test]
+  fn write_file_replaces_existing_content_atomically() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let posix_fs = new_posixfs(&dir.path());
+    let path = PathBuf::from("marmosets");
+    make_file(&dir.path().join(&path), b"old", 0o600);
+    posix_fs
+      .write_file(path.clone(), Bytes::from("new"), false)
+      .wait()
+      .unwrap();
+    assert_eq!(
+      std::fs::read(dir.path().join(&path)).unwrap(),
+      b"new".to_vec()
+    );
+  }
+
+  #[test]
+  fn copy_file_duplicates_content_and_leaves_source_intact() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let posix_fs = new_posixfs(&dir.path());
+    let src = PathBuf::from("marmosets");
+    let dst = PathBuf::from("more_marmosets");
+    make_file(&dir.path().join(&src), b"cute", 0o600);
+    posix_fs.copy_file(src.clone(), dst.clone()).wait().unwrap();
+    assert_eq!(std::fs::read(dir.path().join(&src)).unwrap(), b"cute".to_vec());
+    assert_eq!(std::fs::read(dir.path().join(&dst)).unwrap(), b"cute".to_vec());
+  }
+
+  #[test]
+  fn rename_moves_content_and_removes_source() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let posix_fs = new_posixfs(&dir.path());
+    let src = PathBuf::from("marmosets");
+    let dst = PathBuf::from("relocated_marmosets");
+    make_file(&dir.path().join(&src), b"cute", 0o600);
+    posix_fs.rename(src.clone(), dst.clone()).wait().unwrap();
+    assert!(!dir.path().join(&src).exists());
+    assert_eq!(std::fs::read(dir.path().join(&dst)).unwrap(), b"cute".to_vec());
+  }
+
+  #[test]
+  fn remove_file_deletes_it() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let posix_fs = new_posixfs(&dir.path());
+    let path = PathBuf::from("marmosets");
+    make_file(&dir.path().join(&path), &[], 0o600);
+    posix_fs.remove_file(path.clone()).wait().unwrap();
+    assert!(!dir.path().join(&path).exists());
+  }
+
+  #[test]
+  fn remove_dir_deletes_an_empty_directory() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let posix_fs = new_posixfs(&dir.path());
+    let path = PathBuf::from("enclosure");
+    std::fs::create_dir(dir.path().join(&path)).unwrap();
+    posix_fs.remove_dir(path.clone()).wait().unwrap();
+    assert!(!dir.path().join(&path).exists());
+  }
+
+  #[test]
+  fn remove_dir_non_empty_errors() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let posix_fs = new_posixfs(&dir.path());
+    let path = PathBuf::from("enclosure");
+    std::fs::create_dir(dir.path().join(&path)).unwrap();
+    make_file(&dir.path().join(&path).join("napping_marmoset"), &[], 0o600);
+    posix_fs
+      .remove_dir(path)
+      .wait()
+      .expect_err("Want error for a non-empty directory");
+  }
+
   #[test]
   fn path_stats_for_paths() {
     let dir = tempfile::TempDir::new().unwrap();
@@ -1113,6 +1864,60 @@ mod posixfs_test {
     assert_eq!(v, path_stats);
   }
 
+  #[test]
+  fn path_stats_symlink_two_node_cycle_errors() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let root_path = dir.path();
+
+    std::os::unix::fs::symlink("b", &root_path.join("a")).unwrap();
+    std::os::unix::fs::symlink("a", &root_path.join("b")).unwrap();
+
+    let posix_fs = Arc::new(new_posixfs(&root_path));
+    posix_fs
+      .path_stats(vec![PathBuf::from("a")])
+      .wait()
+      .expect_err("Want error for a symlink cycle");
+  }
+
+  #[test]
+  fn path_stats_symlink_self_cycle_errors() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let root_path = dir.path();
+
+    std::os::unix::fs::symlink("self_link", &root_path.join("self_link")).unwrap();
+
+    let posix_fs = Arc::new(new_posixfs(&root_path));
+    posix_fs
+      .path_stats(vec![PathBuf::from("self_link")])
+      .wait()
+      .expect_err("Want error for a symlink pointing at itself");
+  }
+
+  #[test]
+  fn path_stats_symlink_overlong_chain_errors() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let root_path = dir.path();
+
+    // A chain deeper than `VFS::max_link_depth`'s default of 40, none of which repeat,
+    // so this exercises the depth cap rather than the visited-set cycle check.
+    make_file(&root_path.join("target"), &[], 0o600);
+    let chain_len = 50;
+    std::os::unix::fs::symlink("target", &root_path.join(format!("link_{}", chain_len - 1)))
+      .unwrap();
+    for i in 0..(chain_len - 1) {
+      std::os::unix::fs::symlink(
+        format!("link_{}", i + 1),
+        &root_path.join(format!("link_{}", i)),
+      ).unwrap();
+    }
+
+    let posix_fs = Arc::new(new_posixfs(&root_path));
+    posix_fs
+      .path_stats(vec![PathBuf::from("link_0")])
+      .wait()
+      .expect_err("Want error for an overlong symlink chain");
+  }
+
   fn assert_only_file_is_executable(path: &Path, want_is_executable: bool) {
     let fs = new_posixfs(path);
     let stats = fs.scandir(&Dir(PathBuf::from("."))).wait().unwrap();
@@ -0,0 +1,110 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! The handful of filesystem operations that aren't portable across Unix and Windows: whether a
+//! file counts as executable (a permission bit on Unix; inferred from the extension on Windows,
+//! which has no such bit) and how a symlink gets created (one syscall on Unix; a choice of two,
+//! depending on whether the target is a file or a directory, on Windows).
+//!
+
+#[cfg(unix)]
+pub use self::unix::{create_symlink, is_executable, set_executable};
+#[cfg(windows)]
+pub use self::windows::{create_symlink, is_executable, set_executable};
+
+#[cfg(unix)]
+mod unix {
+  use std::fs;
+  use std::io;
+  use std::os::unix::fs::PermissionsExt;
+  use std::path::Path;
+
+  pub fn is_executable(metadata: &fs::Metadata, _path: &Path) -> bool {
+    metadata.permissions().mode() & 0o100 == 0o100
+  }
+
+  pub fn set_executable(file: &fs::File, executable: bool) -> io::Result<()> {
+    let mode = if executable { 0o755 } else { 0o644 };
+    let mut permissions = file.metadata()?.permissions();
+    permissions.set_mode(mode);
+    file.set_permissions(permissions)
+  }
+
+  pub fn create_symlink(src: &Path, dst: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(src, dst)
+  }
+}
+
+#[cfg(windows)]
+mod windows {
+  use std::ffi::OsStr;
+  use std::fs;
+  use std::io;
+  use std::path::Path;
+
+  /// Windows has no executable permission bit, so we approximate one the way a shell does: by
+  /// extension. Callers who need a different policy (a configurable allowlist, say) should layer
+  /// it on top of this default rather than relying on it being exhaustive.
+  const EXECUTABLE_EXTENSIONS: &[&str] = &["exe", "bat", "cmd"];
+
+  pub fn is_executable(_metadata: &fs::Metadata, path: &Path) -> bool {
+    path
+      .extension()
+      .and_then(OsStr::to_str)
+      .map(|ext| {
+        EXECUTABLE_EXTENSIONS
+          .iter()
+          .any(|candidate| candidate.eq_ignore_ascii_case(ext))
+      })
+      .unwrap_or(false)
+  }
+
+  pub fn set_executable(_file: &fs::File, _executable: bool) -> io::Result<()> {
+    // There is no executable bit to flip: executability is inferred from the path's extension
+    // instead, which the caller already controls by choosing what it names the destination.
+    Ok(())
+  }
+
+  ///
+  /// Creates a symlink at `dst` pointing at `src`, choosing `symlink_dir` (falling back to a
+  /// junction if that fails) or `symlink_file` based on what `src` actually is -- unlike Unix,
+  /// Windows' symlink syscalls are distinct for the two cases.
+  ///
+  pub fn create_symlink(src: &Path, dst: &Path) -> io::Result<()> {
+    if src.is_dir() {
+      std::os::windows::fs::symlink_dir(src, dst)
+        .or_else(|e| create_junction(src, dst).map_err(|_| e))
+    } else {
+      std::os::windows::fs::symlink_file(src, dst)
+    }
+  }
+
+  ///
+  /// Creates an NTFS junction at `dst` pointing at `src`, as a fallback for the (common) case
+  /// where `symlink_dir` failed because the process has neither administrator privileges nor
+  /// Developer Mode enabled -- either of which `symlink_dir` requires but a junction doesn't.
+  ///
+  /// Issuing the underlying reparse-point request directly would mean a raw `DeviceIoControl`
+  /// call, which isn't exposed anywhere in `std` and isn't worth a new FFI dependency for; `cmd`'s
+  /// built-in `mklink /J` does the same thing and is present on every supported Windows version,
+  /// so this shells out to it instead.
+  ///
+  fn create_junction(src: &Path, dst: &Path) -> io::Result<()> {
+    let status = std::process::Command::new("cmd")
+      .arg("/C")
+      .arg("mklink")
+      .arg("/J")
+      .arg(dst)
+      .arg(src)
+      .status()?;
+    if status.success() {
+      Ok(())
+    } else {
+      Err(io::Error::new(
+        io::ErrorKind::Other,
+        format!("`mklink /J {:?} {:?}` failed with {:?}", dst, src, status),
+      ))
+    }
+  }
+}
@@ -0,0 +1,141 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! Streams filesystem change notifications for a `PosixFS` root, so callers can invalidate cached
+//! `stat`/`scandir`/`path_stats` results incrementally instead of rescanning the whole tree. Built
+//! on `notify`, which picks the platform-native event source itself (FSEvents on macOS, inotify on
+//! Linux, ReadDirectoryChangesW on Windows) and already coalesces bursts of raw events over a
+//! short debounce window before handing them to us.
+//!
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc as std_mpsc;
+use std::thread;
+use std::time::Duration;
+
+use futures::sync::mpsc;
+use futures::{Poll, Sink, Stream};
+use notify::{DebouncedEvent, RecursiveMode, Watcher as NotifyWatcher};
+
+/// How long `notify` should coalesce a burst of raw OS events for the same path before handing us
+/// a single `DebouncedEvent`.
+const DEBOUNCE: Duration = Duration::from_millis(50);
+
+/// How many pending events the `futures::Stream` side of a `Watch` will buffer before the relay
+/// thread blocks waiting for the consumer to keep up.
+const CHANNEL_CAPACITY: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WatchEvent {
+  /// One or more paths, relative to the watched root, were created, written, removed, or renamed.
+  Changed(Vec<PathBuf>),
+  /// The kernel's event queue overflowed (or `notify` otherwise lost track of events): any paths
+  /// changed since the last observed event are unknown, so the caller should treat its cached
+  /// state as stale and rescan rather than trust incremental updates until the next `Changed`.
+  Overflow,
+}
+
+///
+/// A live filesystem watch on a single root, exposed as a `futures::Stream` of `WatchEvent`s.
+///
+/// Dropping a `Watch` stops the underlying OS watch and ends the stream, since that's what drops
+/// the `notify::Watcher` keeping it alive.
+///
+pub struct Watch {
+  _watcher: notify::RecommendedWatcher,
+  receiver: mpsc::Receiver<WatchEvent>,
+}
+
+impl Watch {
+  ///
+  /// Begins recursively watching `root` for changes. `root` must already exist.
+  ///
+  pub fn new(root: &Path) -> Result<Watch, String> {
+    let (raw_tx, raw_rx) = std_mpsc::channel();
+    let mut watcher: notify::RecommendedWatcher = NotifyWatcher::new(raw_tx, DEBOUNCE)
+      .map_err(|e| format!("Could not initialize a filesystem watcher: {:?}", e))?;
+    watcher
+      .watch(root, RecursiveMode::Recursive)
+      .map_err(|e| format!("Could not watch {:?}: {:?}", root, e))?;
+
+    let (event_tx, event_rx) = mpsc::channel(CHANNEL_CAPACITY);
+    let root = root.to_owned();
+    // `notify`'s watcher threads are internal to it and deliver raw events via `raw_tx`; this
+    // thread's only job is translating those into our relative-path vocabulary and forwarding them
+    // to whatever is polling the `Stream` half.
+    thread::spawn(move || Self::relay(root, raw_rx, event_tx));
+
+    Ok(Watch {
+      _watcher: watcher,
+      receiver: event_rx,
+    })
+  }
+
+  fn relay(
+    root: PathBuf,
+    raw_events: std_mpsc::Receiver<DebouncedEvent>,
+    event_tx: mpsc::Sender<WatchEvent>,
+  ) {
+    let mut event_tx = event_tx;
+    loop {
+      let event = match raw_events.recv() {
+        Ok(event) => event,
+        // notify's sending half was dropped, which happens when the `RecommendedWatcher` (held by
+        // the `Watch` this thread belongs to) is dropped.
+        Err(_) => return,
+      };
+      if let Some(translated) = Self::translate(&root, event) {
+        match event_tx.send(translated).wait() {
+          Ok(sender) => event_tx = sender,
+          // The `Stream` half (and therefore the `Watch`) was dropped.
+          Err(_) => return,
+        }
+      }
+    }
+  }
+
+  ///
+  /// Converts one `notify` event into our vocabulary: an absolute path (or pair of paths, for a
+  /// rename) relativized to `root`, or `None` for the purely informational events `notify` emits
+  /// before a debounced one (which we'd otherwise double-report).
+  ///
+  fn translate(root: &Path, event: DebouncedEvent) -> Option<WatchEvent> {
+    match event {
+      DebouncedEvent::Create(path) | DebouncedEvent::Write(path) | DebouncedEvent::Chmod(path)
+      | DebouncedEvent::Remove(path) => {
+        Self::relativize(root, &path).map(|p| WatchEvent::Changed(vec![p]))
+      }
+      DebouncedEvent::Rename(src, dst) => {
+        let changed: Vec<PathBuf> = vec![src, dst]
+          .iter()
+          .filter_map(|p| Self::relativize(root, p))
+          .collect();
+        if changed.is_empty() {
+          None
+        } else {
+          Some(WatchEvent::Changed(changed))
+        }
+      }
+      DebouncedEvent::Rescan | DebouncedEvent::Error(_, _) => Some(WatchEvent::Overflow),
+      DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) => None,
+    }
+  }
+
+  fn relativize(root: &Path, absolute: &Path) -> Option<PathBuf> {
+    absolute.strip_prefix(root).ok().map(|p| p.to_owned())
+  }
+}
+
+impl Stream for Watch {
+  type Item = WatchEvent;
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Option<WatchEvent>, io::Error> {
+    self
+      .receiver
+      .poll()
+      .map_err(|()| io::Error::new(io::ErrorKind::Other, "Filesystem watch channel was closed."))
+  }
+}
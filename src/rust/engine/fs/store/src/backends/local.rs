@@ -0,0 +1,169 @@
+// Copyright 2020 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! An on-disk, content-addressed `ByteStore`/`DirectoryStore`: every `Digest` is one file, named
+//! by its hex fingerprint, under a root directory -- so what's stored here survives past the
+//! process that wrote it, the same way the LMDB-backed local store this replaces did. A write
+//! first lands in a uniquely-named temp file in that same root (so the rename that publishes it
+//! is atomic and same-filesystem) and is fsynced before the rename, the same crash-safe sequence
+//! `PosixFS::write_file` uses and for the same reason: a crash partway through should leave either
+//! no file at the final path or a complete one, never a truncated one.
+//!
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use async_trait::async_trait;
+use bazel_protos::remote_execution::Directory;
+use bytes::Bytes;
+use hashing::{Digest, Hasher};
+use protobuf::Message as _;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use super::{ByteStore, DirectoryStore};
+
+/// Bytes read (and hashed) per chunk while streaming a large blob into the store, so a
+/// multi-gigabyte file never requires a multi-gigabyte allocation -- just this one reusable
+/// buffer.
+const STREAM_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Disambiguates concurrent writers' temp files within one process; combined with the pid, this
+/// keeps two `LocalByteStore`s sharing a root (or two writes racing in the same one) from ever
+/// picking the same temp path.
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn temp_path(root: &std::path::Path) -> PathBuf {
+  let n = TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed);
+  root.join(format!(".tmp-{}-{}", std::process::id(), n))
+}
+
+async fn publish(temp_path: &std::path::Path, final_path: &std::path::Path) -> Result<(), String> {
+  fs::rename(temp_path, final_path).await.map_err(|e| {
+    let _ = std::fs::remove_file(temp_path);
+    format!(
+      "Failed to publish {:?} as {:?}: {:?}",
+      temp_path, final_path, e
+    )
+  })
+}
+
+pub struct LocalByteStore {
+  root: PathBuf,
+}
+
+impl LocalByteStore {
+  pub async fn new(root: PathBuf) -> Result<LocalByteStore, String> {
+    fs::create_dir_all(&root)
+      .await
+      .map_err(|e| format!("Failed to create local store directory {:?}: {:?}", root, e))?;
+    Ok(LocalByteStore { root })
+  }
+
+  fn path_for(&self, digest: Digest) -> PathBuf {
+    self.root.join(digest.hash.to_hex())
+  }
+}
+
+#[async_trait]
+impl ByteStore for LocalByteStore {
+  async fn store_bytes(&self, digest: Digest, bytes: Bytes) -> Result<(), String> {
+    let final_path = self.path_for(digest);
+    if fs::try_exists(&final_path).await.unwrap_or(false) {
+      // Already on disk under this content-addressed key: by definition, identical bytes.
+      return Ok(());
+    }
+    let temp_path = temp_path(&self.root);
+    let mut temp_file = fs::File::create(&temp_path)
+      .await
+      .map_err(|e| format!("Failed to create temp file {:?}: {:?}", temp_path, e))?;
+    temp_file
+      .write_all(&bytes)
+      .await
+      .map_err(|e| format!("Failed to write temp file {:?}: {:?}", temp_path, e))?;
+    temp_file
+      .sync_all()
+      .await
+      .map_err(|e| format!("Failed to fsync temp file {:?}: {:?}", temp_path, e))?;
+    drop(temp_file);
+    publish(&temp_path, &final_path).await
+  }
+
+  async fn load_bytes(&self, digest: Digest) -> Result<Option<Bytes>, String> {
+    match fs::read(self.path_for(digest)).await {
+      Ok(bytes) => Ok(Some(Bytes::from(bytes))),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(format!("Failed to read digest {:?} from local store: {:?}", digest, e)),
+    }
+  }
+
+  async fn store_bytes_streamed(
+    &self,
+    reader: &mut (dyn AsyncRead + Unpin + Send + '_),
+  ) -> Result<Digest, String> {
+    let temp_path = temp_path(&self.root);
+    let mut temp_file = fs::File::create(&temp_path)
+      .await
+      .map_err(|e| format!("Failed to create temp file {:?}: {:?}", temp_path, e))?;
+
+    let mut hasher = Hasher::new();
+    let mut buf = vec![0u8; STREAM_CHUNK_BYTES];
+    loop {
+      let n = reader
+        .read(&mut buf)
+        .await
+        .map_err(|e| format!("Failed to read while streaming bytes into the store: {:?}", e))?;
+      if n == 0 {
+        break;
+      }
+      hasher.update(&buf[..n]);
+      temp_file
+        .write_all(&buf[..n])
+        .await
+        .map_err(|e| format!("Failed to write temp file {:?}: {:?}", temp_path, e))?;
+    }
+    temp_file
+      .sync_all()
+      .await
+      .map_err(|e| format!("Failed to fsync temp file {:?}: {:?}", temp_path, e))?;
+    drop(temp_file);
+
+    let digest = hasher.finish();
+    publish(&temp_path, &self.path_for(digest)).await?;
+    Ok(digest)
+  }
+}
+
+pub struct LocalDirectoryStore {
+  bytes: LocalByteStore,
+}
+
+impl LocalDirectoryStore {
+  pub async fn new(root: PathBuf) -> Result<LocalDirectoryStore, String> {
+    Ok(LocalDirectoryStore {
+      bytes: LocalByteStore::new(root).await?,
+    })
+  }
+}
+
+#[async_trait]
+impl DirectoryStore for LocalDirectoryStore {
+  async fn store_directory(&self, digest: Digest, directory: Directory) -> Result<(), String> {
+    let bytes = directory
+      .write_to_bytes()
+      .map_err(|e| format!("Failed to serialize Directory proto: {:?}", e))?;
+    self.bytes.store_bytes(digest, Bytes::from(bytes)).await
+  }
+
+  async fn load_directory(&self, digest: Digest) -> Result<Option<Directory>, String> {
+    match self.bytes.load_bytes(digest).await? {
+      Some(bytes) => {
+        let directory = Directory::parse_from_bytes(&bytes)
+          .map_err(|e| format!("Failed to parse Directory proto for digest {:?}: {:?}", digest, e))?;
+        Ok(Some(directory))
+      }
+      None => Ok(None),
+    }
+  }
+}
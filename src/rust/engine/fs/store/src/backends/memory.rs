@@ -0,0 +1,65 @@
+// Copyright 2020 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! An in-memory `ByteStore`/`DirectoryStore`: nothing here survives past the process that created
+//! it. This is what `Store::in_memory` hands tests (and anything else that doesn't care about
+//! surviving past this process) -- see `backends::local` for the on-disk backend production code
+//! actually runs against.
+//!
+
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use bazel_protos::remote_execution::Directory;
+use bytes::Bytes;
+use hashing::{Digest, Fingerprint};
+use tokio::sync::Mutex;
+
+use super::{ByteStore, DirectoryStore};
+
+#[derive(Default)]
+pub struct InMemoryByteStore {
+  bytes: Mutex<HashMap<Fingerprint, Bytes>>,
+}
+
+impl InMemoryByteStore {
+  pub fn new() -> InMemoryByteStore {
+    InMemoryByteStore::default()
+  }
+}
+
+#[async_trait]
+impl ByteStore for InMemoryByteStore {
+  async fn store_bytes(&self, digest: Digest, bytes: Bytes) -> Result<(), String> {
+    self.bytes.lock().await.insert(digest.hash, bytes);
+    Ok(())
+  }
+
+  async fn load_bytes(&self, digest: Digest) -> Result<Option<Bytes>, String> {
+    Ok(self.bytes.lock().await.get(&digest.hash).cloned())
+  }
+}
+
+#[derive(Default)]
+pub struct InMemoryDirectoryStore {
+  directories: Mutex<HashMap<Fingerprint, Directory>>,
+}
+
+impl InMemoryDirectoryStore {
+  pub fn new() -> InMemoryDirectoryStore {
+    InMemoryDirectoryStore::default()
+  }
+}
+
+#[async_trait]
+impl DirectoryStore for InMemoryDirectoryStore {
+  async fn store_directory(&self, digest: Digest, directory: Directory) -> Result<(), String> {
+    self.directories.lock().await.insert(digest.hash, directory);
+    Ok(())
+  }
+
+  async fn load_directory(&self, digest: Digest) -> Result<Option<Directory>, String> {
+    Ok(self.directories.lock().await.get(&digest.hash).cloned())
+  }
+}
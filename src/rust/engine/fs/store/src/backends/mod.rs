@@ -0,0 +1,59 @@
+// Copyright 2020 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! The persistence layer `Store` delegates to: a `ByteStore` for raw file blobs and a
+//! `DirectoryStore` for `Directory` protos, each keyed by `Digest`. Splitting these out as traits
+//! is what lets the same `Store` API run against an in-memory backend in tests (`memory`), a local
+//! on-disk backend day to day (`local`), or a remote object store in CI (`remote`) -- `Store`
+//! itself doesn't know or care which one it's talking to.
+//!
+
+mod local;
+mod memory;
+mod remote;
+
+pub use local::{LocalByteStore, LocalDirectoryStore};
+pub use memory::{InMemoryByteStore, InMemoryDirectoryStore};
+pub use remote::{RemoteByteStore, RemoteDirectoryStore};
+
+use async_trait::async_trait;
+use bazel_protos::remote_execution::Directory;
+use bytes::Bytes;
+use hashing::Digest;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+#[async_trait]
+pub trait ByteStore: Send + Sync {
+  async fn store_bytes(&self, digest: Digest, bytes: Bytes) -> Result<(), String>;
+  async fn load_bytes(&self, digest: Digest) -> Result<Option<Bytes>, String>;
+
+  /// As `store_bytes`, but for a caller that doesn't already have the whole blob (and its
+  /// `Digest`) in hand -- it reads `reader` to the end, hashing the bytes as they pass through,
+  /// and stores them under whatever `Digest` that hashing produces.
+  ///
+  /// The default implementation here still buffers the entire reader into memory (there's no
+  /// digest to store under until every byte's been seen, and an in-memory backend has nowhere
+  /// else to put bytes in the meantime); `LocalByteStore` overrides this with a real incremental
+  /// implementation that never holds more than one chunk of the blob in memory at a time.
+  async fn store_bytes_streamed(
+    &self,
+    reader: &mut (dyn AsyncRead + Unpin + Send + '_),
+  ) -> Result<Digest, String> {
+    let mut bytes = Vec::new();
+    reader
+      .read_to_end(&mut bytes)
+      .await
+      .map_err(|e| format!("Failed to read while streaming bytes into the store: {:?}", e))?;
+    let bytes = Bytes::from(bytes);
+    let digest = Digest::of_bytes(&bytes);
+    self.store_bytes(digest, bytes).await?;
+    Ok(digest)
+  }
+}
+
+#[async_trait]
+pub trait DirectoryStore: Send + Sync {
+  async fn store_directory(&self, digest: Digest, directory: Directory) -> Result<(), String>;
+  async fn load_directory(&self, digest: Digest) -> Result<Option<Directory>, String>;
+}
@@ -0,0 +1,138 @@
+// Copyright 2020 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! A `ByteStore`/`DirectoryStore` backed by an S3-style remote object store: each `Digest` maps to
+//! one object, keyed by the digest's hex fingerprint, under a caller-provided base URL. A `GET`
+//! is verified against the `Digest` it was fetched for (both the response's `Content-Length` and
+//! a re-hash of the body) before it's trusted, since an object store is outside of this process'
+//! control and could hand back stale or corrupt bytes without either check.
+//!
+
+use async_trait::async_trait;
+use bazel_protos::remote_execution::Directory;
+use bytes::Bytes;
+use hashing::Digest;
+use protobuf::Message as _;
+use reqwest::{Client, StatusCode};
+
+use super::{ByteStore, DirectoryStore};
+
+fn object_url(base_url: &str, digest: Digest) -> String {
+  format!("{}/{}", base_url.trim_end_matches('/'), digest.hash)
+}
+
+async fn put(client: &Client, base_url: &str, digest: Digest, bytes: Bytes) -> Result<(), String> {
+  let response = client
+    .put(object_url(base_url, digest))
+    .body(bytes)
+    .send()
+    .await
+    .map_err(|e| format!("Failed to PUT digest {:?} to remote store: {:?}", digest, e))?;
+  if !response.status().is_success() {
+    return Err(format!(
+      "Remote store rejected PUT of digest {:?}: {}",
+      digest,
+      response.status()
+    ));
+  }
+  Ok(())
+}
+
+async fn get(client: &Client, base_url: &str, digest: Digest) -> Result<Option<Bytes>, String> {
+  let response = client
+    .get(object_url(base_url, digest))
+    .send()
+    .await
+    .map_err(|e| format!("Failed to GET digest {:?} from remote store: {:?}", digest, e))?;
+  if response.status() == StatusCode::NOT_FOUND {
+    return Ok(None);
+  }
+  if !response.status().is_success() {
+    return Err(format!(
+      "Remote store rejected GET of digest {:?}: {}",
+      digest,
+      response.status()
+    ));
+  }
+  if let Some(content_length) = response.content_length() {
+    if content_length as usize != digest.size_bytes {
+      return Err(format!(
+        "Remote store returned {} bytes for digest {:?}, which declares a size of {} bytes",
+        content_length, digest, digest.size_bytes
+      ));
+    }
+  }
+  let bytes = response
+    .bytes()
+    .await
+    .map_err(|e| format!("Failed to read remote store response for digest {:?}: {:?}", digest, e))?;
+  let actual = Digest::of_bytes(&bytes);
+  if actual != digest {
+    return Err(format!(
+      "Remote store returned bytes hashing to {:?} when asked for digest {:?}",
+      actual, digest
+    ));
+  }
+  Ok(Some(bytes))
+}
+
+pub struct RemoteByteStore {
+  client: Client,
+  base_url: String,
+}
+
+impl RemoteByteStore {
+  pub fn new(base_url: String) -> RemoteByteStore {
+    RemoteByteStore {
+      client: Client::new(),
+      base_url,
+    }
+  }
+}
+
+#[async_trait]
+impl ByteStore for RemoteByteStore {
+  async fn store_bytes(&self, digest: Digest, bytes: Bytes) -> Result<(), String> {
+    put(&self.client, &self.base_url, digest, bytes).await
+  }
+
+  async fn load_bytes(&self, digest: Digest) -> Result<Option<Bytes>, String> {
+    get(&self.client, &self.base_url, digest).await
+  }
+}
+
+pub struct RemoteDirectoryStore {
+  client: Client,
+  base_url: String,
+}
+
+impl RemoteDirectoryStore {
+  pub fn new(base_url: String) -> RemoteDirectoryStore {
+    RemoteDirectoryStore {
+      client: Client::new(),
+      base_url,
+    }
+  }
+}
+
+#[async_trait]
+impl DirectoryStore for RemoteDirectoryStore {
+  async fn store_directory(&self, digest: Digest, directory: Directory) -> Result<(), String> {
+    let bytes = directory
+      .write_to_bytes()
+      .map_err(|e| format!("Failed to serialize Directory proto: {:?}", e))?;
+    put(&self.client, &self.base_url, digest, Bytes::from(bytes)).await
+  }
+
+  async fn load_directory(&self, digest: Digest) -> Result<Option<Directory>, String> {
+    match get(&self.client, &self.base_url, digest).await? {
+      Some(bytes) => {
+        let directory = Directory::parse_from_bytes(&bytes)
+          .map_err(|e| format!("Failed to parse Directory proto for digest {:?}: {:?}", digest, e))?;
+        Ok(Some(directory))
+      }
+      None => Ok(None),
+    }
+  }
+}
@@ -0,0 +1,148 @@
+// Copyright 2020 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! A structural diff between two stored `Directory` trees, for callers (incremental rebuilds,
+//! cache invalidation) that need to know exactly what changed rather than just whether anything
+//! did.
+//!
+
+use std::path::{Path, PathBuf};
+
+use hashing::Digest;
+
+use crate::Store;
+
+/// The result of comparing two `Directory` trees: which paths only the left tree had, which only
+/// the right had, and which appear in both but with different contents -- plus, for every
+/// directory whose `Digest`s differ between the two sides, those `Digest`s; a directory not
+/// listed there is identical on both sides (or absent from both).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TreeDiff {
+  pub added: Vec<PathBuf>,
+  pub removed: Vec<PathBuf>,
+  pub changed: Vec<PathBuf>,
+  /// Every directory path whose `Digest`s differ between the two sides, paired with its `Digest`
+  /// on each side it was present on -- `None` on a side means that directory didn't exist there.
+  /// Directories with equal `Digest`s are pruned and not listed.
+  pub directory_digests: Vec<(PathBuf, Option<Digest>, Option<Digest>)>,
+}
+
+impl Store {
+  ///
+  /// Computes a `TreeDiff` between the trees at `left` and `right`, descending into matching
+  /// subdirectories only when their digests disagree -- two subtrees with the same `Digest` are
+  /// known to be identical without reading either one, so the whole branch is pruned in O(1).
+  ///
+  pub(crate) async fn diff(&self, left: Digest, right: Digest) -> Result<TreeDiff, String> {
+    let mut result = TreeDiff::default();
+    self
+      .diff_directories(Path::new(""), Some(left), Some(right), &mut result)
+      .await?;
+    Ok(result)
+  }
+
+  fn diff_directories<'a>(
+    &'a self,
+    prefix: &'a Path,
+    left: Option<Digest>,
+    right: Option<Digest>,
+    result: &'a mut TreeDiff,
+  ) -> futures::future::BoxFuture<'a, Result<(), String>> {
+    Box::pin(async move {
+      if left == right {
+        // Equal (including both-`None`, which can't actually happen here, and both-`Some` with
+        // matching digests) means this whole branch is identical: nothing to record or descend
+        // into.
+        return Ok(());
+      }
+      if !prefix.as_os_str().is_empty() || left.is_some() || right.is_some() {
+        result
+          .directory_digests
+          .push((prefix.to_owned(), left, right));
+      }
+
+      let left_dir = match left {
+        Some(digest) => Some(
+          self
+            .load_directory(digest)
+            .await?
+            .ok_or_else(|| format!("Directory for digest {:?} was not found in the store", digest))?,
+        ),
+        None => None,
+      };
+      let right_dir = match right {
+        Some(digest) => Some(
+          self
+            .load_directory(digest)
+            .await?
+            .ok_or_else(|| format!("Directory for digest {:?} was not found in the store", digest))?,
+        ),
+        None => None,
+      };
+
+      let left_files: Vec<_> = left_dir.iter().flat_map(|d| d.get_files()).collect();
+      let right_files: Vec<_> = right_dir.iter().flat_map(|d| d.get_files()).collect();
+      for left_file in &left_files {
+        let path = prefix.join(left_file.get_name());
+        match right_files.iter().find(|f| f.get_name() == left_file.get_name()) {
+          Some(right_file) => {
+            if right_file.get_digest() != left_file.get_digest() {
+              result.changed.push(path);
+            }
+          }
+          None => result.removed.push(path),
+        }
+      }
+      for right_file in &right_files {
+        if !left_files.iter().any(|f| f.get_name() == right_file.get_name()) {
+          result.added.push(prefix.join(right_file.get_name()));
+        }
+      }
+
+      let left_symlinks: Vec<_> = left_dir.iter().flat_map(|d| d.get_symlinks()).collect();
+      let right_symlinks: Vec<_> = right_dir.iter().flat_map(|d| d.get_symlinks()).collect();
+      for left_symlink in &left_symlinks {
+        let path = prefix.join(left_symlink.get_name());
+        match right_symlinks.iter().find(|s| s.get_name() == left_symlink.get_name()) {
+          Some(right_symlink) => {
+            if right_symlink.get_target() != left_symlink.get_target() {
+              result.changed.push(path);
+            }
+          }
+          None => result.removed.push(path),
+        }
+      }
+      for right_symlink in &right_symlinks {
+        if !left_symlinks.iter().any(|s| s.get_name() == right_symlink.get_name()) {
+          result.added.push(prefix.join(right_symlink.get_name()));
+        }
+      }
+
+      let left_dirs: Vec<_> = left_dir.iter().flat_map(|d| d.get_directories()).collect();
+      let right_dirs: Vec<_> = right_dir.iter().flat_map(|d| d.get_directories()).collect();
+      let mut names: Vec<&str> = left_dirs
+        .iter()
+        .map(|d| d.get_name())
+        .chain(right_dirs.iter().map(|d| d.get_name()))
+        .collect();
+      names.sort_unstable();
+      names.dedup();
+      for name in names {
+        let left_child = left_dirs
+          .iter()
+          .find(|d| d.get_name() == name)
+          .map(|d| d.get_digest().into());
+        let right_child = right_dirs
+          .iter()
+          .find(|d| d.get_name() == name)
+          .map(|d| d.get_digest().into());
+        self
+          .diff_directories(&prefix.join(name), left_child, right_child, result)
+          .await?;
+      }
+
+      Ok(())
+    })
+  }
+}
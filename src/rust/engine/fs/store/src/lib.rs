@@ -0,0 +1,236 @@
+// Copyright 2020 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+#![deny(warnings)]
+// Enable all clippy lints except for many of the pedantic ones. It's a shame this needs to be
+// copied and pasted across crates, but there doesn't appear to be a way to include inner
+// attributes from a common source.
+#![deny(
+  clippy::all,
+  clippy::default_trait_access,
+  clippy::expl_impl_clone_on_copy,
+  clippy::if_not_else,
+  clippy::needless_continue,
+  clippy::single_match_else,
+  clippy::unseparated_literal_suffix,
+  clippy::used_underscore_binding
+)]
+// It is often more clear to show that nothing is being moved.
+#![allow(clippy::match_ref_pats)]
+// Subjective style.
+#![allow(clippy::len_without_is_empty, clippy::redundant_field_names)]
+// Default isn't as big a deal as people seem to think it is.
+#![allow(clippy::new_without_default)]
+// Arc<Mutex> can be more clear than needing to grok Orderings:
+#![allow(clippy::mutex_atomic)]
+
+mod backends;
+pub use crate::backends::{
+  ByteStore, DirectoryStore, InMemoryByteStore, InMemoryDirectoryStore, LocalByteStore,
+  LocalDirectoryStore, RemoteByteStore, RemoteDirectoryStore,
+};
+mod diff;
+pub use crate::diff::TreeDiff;
+mod snapshot_ops;
+pub use crate::snapshot_ops::{SnapshotOps, SubsetParams};
+mod tar_ingest;
+mod tar_export;
+pub use crate::tar_export::TarMode;
+
+#[cfg(test)]
+mod snapshot_ops_tests;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use bazel_protos::remote_execution::Directory;
+use bytes::Bytes;
+use fs::{PathStat, PosixFS};
+use hashing::Digest;
+use tokio::io::AsyncRead;
+
+/// One of the two kinds of entry a content-addressed tree node can be: either a blob of file
+/// bytes, or a `Directory` proto describing a subdirectory's own entries.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EntryType {
+  File,
+  Directory,
+}
+
+/// A content-addressed store of file blobs and the `Directory` protos that describe how they're
+/// arranged into trees, keyed throughout by `Digest` (a fingerprint plus a byte length, so two
+/// different contents can never collide under one key).
+///
+/// This is a thin, cheaply-`Clone`able handle: the actual blobs and directories live behind the
+/// `ByteStore`/`DirectoryStore` backends in the shared `Inner`, behind an `Arc`, the same way
+/// `PosixFS` hands out cheap handles onto a shared root. `local` is always consulted first; when
+/// `remote` is present, a miss there falls through to it and populates `local` with what it
+/// finds, so `merge`/`subset` transparently pull in whatever directory nodes aren't already on
+/// this machine.
+#[derive(Clone)]
+pub struct Store {
+  inner: Arc<Inner>,
+}
+
+struct Inner {
+  local_bytes: Arc<dyn ByteStore>,
+  local_directories: Arc<dyn DirectoryStore>,
+  remote: Option<(Arc<dyn ByteStore>, Arc<dyn DirectoryStore>)>,
+}
+
+impl Store {
+  /// A `Store` backed only by an in-memory backend, with no remote fallback -- what tests, and
+  /// anything else that doesn't care about surviving past this process, should reach for.
+  pub fn in_memory() -> Store {
+    Store {
+      inner: Arc::new(Inner {
+        local_bytes: Arc::new(InMemoryByteStore::new()),
+        local_directories: Arc::new(InMemoryDirectoryStore::new()),
+        remote: None,
+      }),
+    }
+  }
+
+  /// A `Store` backed by an on-disk local store rooted at `local_store_dir`, with no remote
+  /// fallback -- what a real `pants` invocation runs against day to day.
+  pub async fn local_only(local_store_dir: PathBuf) -> Result<Store, String> {
+    Ok(Store {
+      inner: Arc::new(Inner {
+        local_bytes: Arc::new(LocalByteStore::new(local_store_dir.join("files")).await?),
+        local_directories: Arc::new(LocalDirectoryStore::new(local_store_dir.join("directories")).await?),
+        remote: None,
+      }),
+    })
+  }
+
+  /// As `local_only`, but falling through to a remote object store (addressed by
+  /// `remote_base_url`) for whatever the on-disk local store doesn't already have.
+  pub async fn with_remote(local_store_dir: PathBuf, remote_base_url: String) -> Result<Store, String> {
+    Ok(Store {
+      inner: Arc::new(Inner {
+        local_bytes: Arc::new(LocalByteStore::new(local_store_dir.join("files")).await?),
+        local_directories: Arc::new(LocalDirectoryStore::new(local_store_dir.join("directories")).await?),
+        remote: Some((
+          Arc::new(RemoteByteStore::new(remote_base_url.clone())),
+          Arc::new(RemoteDirectoryStore::new(remote_base_url)),
+        )),
+      }),
+    })
+  }
+
+  pub async fn store_file_bytes(&self, bytes: Bytes) -> Result<Digest, String> {
+    let digest = Digest::of_bytes(&bytes);
+    self.inner.local_bytes.store_bytes(digest, bytes).await?;
+    Ok(digest)
+  }
+
+  /// As `store_file_bytes`, but for a reader whose contents aren't already buffered in memory:
+  /// the bytes are hashed as they're read rather than requiring a second full-size buffer just to
+  /// compute a `Digest` up front. See `tar_ingest::hash_streaming`, whose entire purpose is to
+  /// avoid exactly that second buffer for large archive entries.
+  pub async fn store_file_bytes_streamed<R: AsyncRead + Unpin + Send>(
+    &self,
+    reader: &mut R,
+  ) -> Result<Digest, String> {
+    self.inner.local_bytes.store_bytes_streamed(reader).await
+  }
+
+  pub async fn load_file_bytes(&self, digest: Digest) -> Result<Option<Bytes>, String> {
+    if let Some(bytes) = self.inner.local_bytes.load_bytes(digest).await? {
+      return Ok(Some(bytes));
+    }
+    let Some((remote_bytes, _)) = &self.inner.remote else {
+      return Ok(None);
+    };
+    match remote_bytes.load_bytes(digest).await? {
+      Some(bytes) => {
+        self.inner.local_bytes.store_bytes(digest, bytes.clone()).await?;
+        Ok(Some(bytes))
+      }
+      None => Ok(None),
+    }
+  }
+
+  pub async fn record_directory(&self, directory: &Directory) -> Result<Digest, String> {
+    let bytes = directory
+      .write_to_bytes()
+      .map_err(|e| format!("Failed to serialize Directory proto: {:?}", e))?;
+    let digest = Digest::of_bytes(&bytes);
+    self
+      .inner
+      .local_directories
+      .store_directory(digest, directory.clone())
+      .await?;
+    Ok(digest)
+  }
+
+  pub async fn load_directory(&self, digest: Digest) -> Result<Option<Directory>, String> {
+    if let Some(directory) = self.inner.local_directories.load_directory(digest).await? {
+      return Ok(Some(directory));
+    }
+    let Some((_, remote_directories)) = &self.inner.remote else {
+      return Ok(None);
+    };
+    match remote_directories.load_directory(digest).await? {
+      Some(directory) => {
+        self
+          .inner
+          .local_directories
+          .store_directory(digest, directory.clone())
+          .await?;
+        Ok(Some(directory))
+      }
+      None => Ok(None),
+    }
+  }
+}
+
+/// A content-addressed snapshot of a directory tree: a `Digest` identifying the root `Directory`
+/// proto, plus the `PathStat`s it was computed from.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+  pub digest: Digest,
+  pub path_stats: Vec<PathStat>,
+}
+
+/// Computes a `Digest` for a single file's contents on demand, so callers building a `Snapshot`
+/// from a walked directory don't need to read and hash a file more than once. `Store::ingest_tar`
+/// does not use it: it hashes archive entries itself.
+pub trait StoreFileByDigest<Error> {
+  fn store_by_digest(&self, path_stat: PathStat) -> futures::future::BoxFuture<'static, Result<Digest, Error>>;
+}
+
+/// Pairs a `Store` with the `PosixFS` whose files are to be stored into it, for the common case
+/// where there's no pre-existing content-addressed cache to consult first. This type does not
+/// implement `StoreFileByDigest`.
+#[derive(Clone)]
+pub struct OneOffStoreFileByDigest {
+  store: Store,
+  posix_fs: Arc<PosixFS>,
+}
+
+impl OneOffStoreFileByDigest {
+  pub fn new(store: Store, posix_fs: Arc<PosixFS>) -> OneOffStoreFileByDigest {
+    OneOffStoreFileByDigest { store, posix_fs }
+  }
+}
+
+impl Snapshot {
+  ///
+  /// Materializes a `Snapshot` directly from a tar archive, via `Store::ingest_tar`, without ever
+  /// unpacking it to disk first.
+  ///
+  /// There's no local directory walk backing this snapshot, so there are no `PathStat`s to
+  /// report: the returned `path_stats` is always empty.
+  ///
+  pub async fn from_archive<R: tokio::io::AsyncRead + Unpin + Send + 'static>(
+    store: Store,
+    archive: R,
+  ) -> Result<Snapshot, String> {
+    let digest = store.ingest_tar(archive).await?;
+    Ok(Snapshot {
+      digest,
+      path_stats: Vec::new(),
+    })
+  }
+}
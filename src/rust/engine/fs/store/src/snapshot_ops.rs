@@ -0,0 +1,227 @@
+// Copyright 2020 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! Tree-shaped operations over `Directory` digests: combining several trees into one (`merge`)
+//! and narrowing a tree down to the paths a set of globs selects (`subset`). Both work purely in
+//! terms of already-stored `Directory` protos, so neither ever touches the local filesystem.
+//!
+
+use std::collections::HashMap;
+
+use bazel_protos::remote_execution::{Directory, DirectoryNode, FileNode, SymlinkNode};
+use fs::{GlobExpansionConjunction, PreparedPathGlobs};
+use glob::Pattern;
+use hashing::Digest;
+
+use crate::diff::TreeDiff;
+use crate::Store;
+
+/// The globs a `subset` call should narrow a tree down to.
+pub struct SubsetParams {
+  pub globs: PreparedPathGlobs,
+}
+
+/// One filespec's remaining per-component `Pattern`s still to match, as `subset_directory`
+/// descends the tree: a suffix of one of `PreparedPathGlobs::filespecs`, advanced one
+/// `Directory` level at a time.
+struct GlobState<'a>(&'a [Pattern]);
+
+impl<'a> GlobState<'a> {
+  /// The state this glob is left in after a directory or file/symlink entry named `name` is
+  /// consumed, or `None` if `name` couldn't have been produced by this glob at all. A `**`
+  /// component matches zero or more path components, so it either is satisfied already (try
+  /// whatever comes after it against `name`) or consumes `name` itself and stays in play for
+  /// whatever comes next.
+  fn advance(&self, name: &str) -> Option<GlobState<'a>> {
+    match self.0.split_first() {
+      Some((head, rest)) if head.as_str() == "**" => {
+        GlobState(rest).advance(name).or(Some(GlobState(self.0)))
+      }
+      Some((head, rest)) if head.matches(name) => Some(GlobState(rest)),
+      _ => None,
+    }
+  }
+
+  /// Nothing left to match, or only `**` components, which can match zero path components.
+  fn is_terminal(&self) -> bool {
+    self.0.iter().all(|p| p.as_str() == "**")
+  }
+}
+
+/// Whether the given per-filespec states (each already advanced to the same tree depth) select
+/// the path they were advanced along, under `conjunction`.
+fn combine(
+  states: &[Option<GlobState>],
+  terminal: impl Fn(&GlobState) -> bool,
+  conjunction: GlobExpansionConjunction,
+) -> bool {
+  let mut matched = states.iter().map(|state| state.as_ref().is_some_and(&terminal));
+  match conjunction {
+    GlobExpansionConjunction::AllMatch => matched.all(|m| m),
+    GlobExpansionConjunction::AnyMatch => matched.any(|m| m),
+  }
+}
+
+/// Narrows `directory` down to the entries selected by `states` (one per filespec in the
+/// original `PreparedPathGlobs`, each already advanced to `directory`'s own depth in the tree),
+/// recursing into subdirectories and re-storing a filtered copy of each one that still has a
+/// live glob state left to match against.
+fn subset_directory<'a>(
+  store: &'a Store,
+  directory: Directory,
+  states: Vec<Option<GlobState<'a>>>,
+  conjunction: GlobExpansionConjunction,
+) -> futures::future::BoxFuture<'a, Result<Directory, String>> {
+  Box::pin(async move {
+    let mut files: Vec<FileNode> = Vec::new();
+    for file_node in directory.get_files() {
+      let advanced: Vec<Option<GlobState>> = states
+        .iter()
+        .map(|state| state.as_ref().and_then(|s| s.advance(file_node.get_name())))
+        .collect();
+      if combine(&advanced, GlobState::is_terminal, conjunction) {
+        files.push(file_node.clone());
+      }
+    }
+
+    let mut symlinks: Vec<SymlinkNode> = Vec::new();
+    for symlink_node in directory.get_symlinks() {
+      let advanced: Vec<Option<GlobState>> = states
+        .iter()
+        .map(|state| state.as_ref().and_then(|s| s.advance(symlink_node.get_name())))
+        .collect();
+      if combine(&advanced, GlobState::is_terminal, conjunction) {
+        symlinks.push(symlink_node.clone());
+      }
+    }
+
+    let mut dirs: Vec<DirectoryNode> = Vec::new();
+    for dir_node in directory.get_directories() {
+      let child_states: Vec<Option<GlobState>> = states
+        .iter()
+        .map(|state| state.as_ref().and_then(|s| s.advance(dir_node.get_name())))
+        .collect();
+      if !combine(&child_states, |_| true, conjunction) {
+        // Not even one filespec (or, under `AllMatch`, not every filespec) could still match
+        // something under this subdirectory: there's nothing left here worth recursing into.
+        continue;
+      }
+      let child_digest: Digest = dir_node.get_digest().into();
+      let child_directory = store
+        .load_directory(child_digest)
+        .await?
+        .ok_or_else(|| format!("Directory for digest {:?} was not found in the store", child_digest))?;
+      let filtered_child = subset_directory(store, child_directory, child_states, conjunction).await?;
+      let mut node = DirectoryNode::new();
+      node.set_name(dir_node.get_name().to_owned());
+      node.set_digest(store.record_directory(&filtered_child).await?.into());
+      dirs.push(node);
+    }
+
+    files.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+    dirs.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+    symlinks.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+    let mut subset_dir = Directory::new();
+    subset_dir.set_files(files.into());
+    subset_dir.set_directories(dirs.into());
+    subset_dir.set_symlinks(symlinks.into());
+    Ok(subset_dir)
+  })
+}
+
+/// Digest-level operations on stored trees, implemented for `Store` so callers reach them the
+/// same way they reach the blob/directory accessors.
+pub trait SnapshotOps {
+  fn merge(self, digests: Vec<Digest>) -> futures::future::BoxFuture<'static, Result<Digest, String>>;
+  fn subset(
+    self,
+    digest: Digest,
+    params: SubsetParams,
+  ) -> futures::future::BoxFuture<'static, Result<Digest, String>>;
+  fn diff(
+    self,
+    left: Digest,
+    right: Digest,
+  ) -> futures::future::BoxFuture<'static, Result<TreeDiff, String>>;
+}
+
+impl SnapshotOps for Store {
+  fn merge(self, digests: Vec<Digest>) -> futures::future::BoxFuture<'static, Result<Digest, String>> {
+    Box::pin(async move {
+      let mut merged = Directory::new();
+      let mut seen_files: HashMap<String, FileNode> = HashMap::new();
+      let mut seen_dirs: HashMap<String, Vec<Digest>> = HashMap::new();
+      let mut seen_symlinks: HashMap<String, SymlinkNode> = HashMap::new();
+      for digest in digests {
+        let directory = self
+          .load_directory(digest)
+          .await?
+          .ok_or_else(|| format!("Directory for digest {:?} was not found in the store", digest))?;
+        for file_node in directory.get_files() {
+          seen_files.insert(file_node.get_name().to_owned(), file_node.clone());
+        }
+        for dir_node in directory.get_directories() {
+          seen_dirs
+            .entry(dir_node.get_name().to_owned())
+            .or_insert_with(Vec::new)
+            .push(dir_node.get_digest().into());
+        }
+        for symlink_node in directory.get_symlinks() {
+          seen_symlinks.insert(symlink_node.get_name().to_owned(), symlink_node.clone());
+        }
+      }
+      let mut files: Vec<FileNode> = seen_files.into_values().collect();
+      files.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+      let mut dirs: Vec<DirectoryNode> = Vec::new();
+      for (name, child_digests) in seen_dirs {
+        // Same-named subdirectories from several inputs are merged recursively.
+        let child_digest = if child_digests.len() == 1 {
+          child_digests[0]
+        } else {
+          self.clone().merge(child_digests).await?
+        };
+        let mut node = DirectoryNode::new();
+        node.set_name(name);
+        node.set_digest(child_digest.into());
+        dirs.push(node);
+      }
+      dirs.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+      let mut symlinks: Vec<SymlinkNode> = seen_symlinks.into_values().collect();
+      symlinks.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+      merged.set_files(files.into());
+      merged.set_directories(dirs.into());
+      merged.set_symlinks(symlinks.into());
+      self.record_directory(&merged).await
+    })
+  }
+
+  fn subset(
+    self,
+    digest: Digest,
+    params: SubsetParams,
+  ) -> futures::future::BoxFuture<'static, Result<Digest, String>> {
+    Box::pin(async move {
+      let directory = self
+        .load_directory(digest)
+        .await?
+        .ok_or_else(|| format!("Directory for digest {:?} was not found in the store", digest))?;
+      let states: Vec<Option<GlobState>> = params
+        .globs
+        .filespecs()
+        .iter()
+        .map(|patterns| Some(GlobState(patterns)))
+        .collect();
+      let subset_dir = subset_directory(&self, directory, states, params.globs.conjunction()).await?;
+      self.record_directory(&subset_dir).await
+    })
+  }
+
+  fn diff(
+    self,
+    left: Digest,
+    right: Digest,
+  ) -> futures::future::BoxFuture<'static, Result<TreeDiff, String>> {
+    Box::pin(async move { Store::diff(&self, left, right).await })
+  }
+}
@@ -0,0 +1,142 @@
+// Copyright 2020 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! The inverse of `tar_ingest`: renders a stored `Directory` tree back out as a tar stream,
+//! without ever materializing it to disk first.
+//!
+
+use std::io;
+
+use bazel_protos::remote_execution::Directory;
+use hashing::Digest;
+use tokio::io::AsyncWrite;
+use tokio_tar::{Builder, EntryType as TarEntryType, Header};
+
+use crate::Store;
+
+/// Whether `materialize_tar` should preserve each file's stored executable bit, or normalize
+/// every file to one fixed mode -- useful when two snapshots that differ only in who happened to
+/// run `chmod` should still produce byte-identical archives.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TarMode {
+  Preserve,
+  Normalize,
+}
+
+/// The mtime every entry is stamped with, so that the only thing that can vary between two tar
+/// streams rendered from the same `Digest` is something we've gotten wrong -- not ambient clock
+/// state.
+const FIXED_MTIME: u64 = 0;
+
+impl Store {
+  ///
+  /// Recursively walks the `Directory` tree rooted at `digest`, writing a deterministic tar
+  /// stream to `dest`: entries are visited in the same sorted order `Directory` protos already
+  /// store their children in, every entry is stamped with a fixed mtime, and `mode` controls
+  /// whether a file's stored executable bit is preserved or normalized away -- so two calls given
+  /// the same `digest` and `mode` always produce byte-identical bytes.
+  ///
+  pub async fn materialize_tar<W: AsyncWrite + Unpin + Send + 'static>(
+    &self,
+    digest: Digest,
+    dest: W,
+    mode: TarMode,
+  ) -> Result<(), String> {
+    let mut builder = Builder::new(dest);
+    self.append_directory(&mut builder, digest, "", mode).await?;
+    builder
+      .finish()
+      .await
+      .map_err(|e| format!("Failed to finish tar stream: {:?}", e))
+  }
+
+  fn append_directory<'a, W: AsyncWrite + Unpin + Send + 'static>(
+    &'a self,
+    builder: &'a mut Builder<W>,
+    digest: Digest,
+    prefix: &'a str,
+    mode: TarMode,
+  ) -> futures::future::BoxFuture<'a, Result<(), String>> {
+    Box::pin(async move {
+      let directory = self
+        .load_directory(digest)
+        .await?
+        .ok_or_else(|| format!("Directory for digest {:?} was not found in the store", digest))?;
+
+      if !prefix.is_empty() {
+        append_dir_header(builder, prefix).await?;
+      }
+
+      for file_node in directory.get_files() {
+        let path = join(prefix, file_node.get_name());
+        let file_digest: Digest = file_node.get_digest().into();
+        let bytes = self
+          .load_file_bytes(file_digest)
+          .await?
+          .ok_or_else(|| format!("File contents for {:?} were not found in the store", path))?;
+        let executable = match mode {
+          TarMode::Preserve => file_node.get_is_executable(),
+          TarMode::Normalize => false,
+        };
+        let mut header = Header::new_gnu();
+        header.set_entry_type(TarEntryType::Regular);
+        header.set_size(bytes.len() as u64);
+        header.set_mode(if executable { 0o755 } else { 0o644 });
+        header.set_mtime(FIXED_MTIME);
+        header.set_cksum();
+        builder
+          .append_data(&mut header, &path, bytes.as_ref())
+          .await
+          .map_err(|e| format!("Failed to append {:?} to tar stream: {:?}", path, e))?;
+      }
+
+      for symlink_node in directory.get_symlinks() {
+        let path = join(prefix, symlink_node.get_name());
+        let mut header = Header::new_gnu();
+        header.set_entry_type(TarEntryType::Symlink);
+        header.set_size(0);
+        header.set_mtime(FIXED_MTIME);
+        header.set_cksum();
+        builder
+          .append_link(&mut header, &path, symlink_node.get_target())
+          .await
+          .map_err(|e| format!("Failed to append symlink {:?} to tar stream: {:?}", path, e))?;
+      }
+
+      for dir_node in directory.get_directories() {
+        let child_prefix = join(prefix, dir_node.get_name());
+        let child_digest: Digest = dir_node.get_digest().into();
+        self
+          .append_directory(builder, child_digest, &child_prefix, mode)
+          .await?;
+      }
+
+      Ok(())
+    })
+  }
+}
+
+async fn append_dir_header<W: AsyncWrite + Unpin + Send + 'static>(
+  builder: &mut Builder<W>,
+  path: &str,
+) -> Result<(), String> {
+  let mut header = Header::new_gnu();
+  header.set_entry_type(TarEntryType::Directory);
+  header.set_size(0);
+  header.set_mode(0o755);
+  header.set_mtime(FIXED_MTIME);
+  header.set_cksum();
+  builder
+    .append_data(&mut header, path, io::empty())
+    .await
+    .map_err(|e| format!("Failed to append directory {:?} to tar stream: {:?}", path, e))
+}
+
+fn join(prefix: &str, name: &str) -> String {
+  if prefix.is_empty() {
+    name.to_owned()
+  } else {
+    format!("{}/{}", prefix, name)
+  }
+}
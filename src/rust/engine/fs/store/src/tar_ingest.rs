@@ -0,0 +1,302 @@
+// Copyright 2020 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! Ingests a tar (optionally gzipped) archive directly into a `Store`, without ever unpacking it
+//! to disk: entries are streamed out of the archive, hashed, and uploaded as they're read, and
+//! assembled into `Directory` protos as the archive's directory structure becomes clear.
+//!
+
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use bazel_protos::remote_execution::{Directory, DirectoryNode, FileNode, SymlinkNode};
+use bytes::BytesMut;
+use hashing::Digest;
+use petgraph::graph::{DiGraph, NodeIndex};
+use petgraph::visit::DfsPostOrder;
+use petgraph::Direction;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio_tar::{Archive, EntryType as TarEntryType};
+
+use crate::Store;
+
+/// Total bytes of file content this ingest will read into memory or have in flight to the store
+/// at once, across every concurrently-uploading entry. A weighted semaphore permit equal to a
+/// file's size is what enforces this: a single enormous file still fits (it just claims the
+/// whole budget to itself and blocks siblings), while many small files run concurrently up to
+/// the cap.
+const MAX_IN_FLIGHT_BYTES: u32 = 128 * 1024 * 1024;
+
+/// Entries at or under this size are small enough to buffer fully in memory and hand off to a
+/// background task; anything larger streams through a hashing reader instead, so a single huge
+/// file in the archive doesn't require a second huge buffer for its hash.
+const BUFFER_THRESHOLD_BYTES: u64 = 1024 * 1024;
+
+#[derive(Clone, Debug)]
+enum Node {
+  Dir,
+  File(Digest),
+  Symlink(PathBuf),
+}
+
+impl Store {
+  ///
+  /// Streams `archive` (a `.tar` or `.tar.gz`, detected by the caller via `GzipDecoder` wrapping
+  /// if needed) into this `Store`, returning the `Digest` of the `Directory` proto at its root.
+  ///
+  /// Entries are pushed onto a graph keyed by normalized path as they're read off of the archive
+  /// in order, synthesizing any parent directory entries the archive didn't list explicitly; once
+  /// the archive is exhausted, the graph is walked in post-order so every directory's children
+  /// are already-stored `Digest`s by the time that directory's own `Directory` proto is built.
+  ///
+  pub async fn ingest_tar<R: AsyncRead + Unpin + Send + 'static>(
+    &self,
+    archive: R,
+  ) -> Result<Digest, String> {
+    let mut graph: DiGraph<(PathBuf, Node), ()> = DiGraph::new();
+    let mut nodes: HashMap<PathBuf, NodeIndex> = HashMap::new();
+    let root = *nodes
+      .entry(PathBuf::new())
+      .or_insert_with(|| graph.add_node((PathBuf::new(), Node::Dir)));
+
+    let semaphore = Arc::new(Semaphore::new(MAX_IN_FLIGHT_BYTES as usize));
+    let mut uploads: JoinSet<Result<(), String>> = JoinSet::new();
+
+    let mut tar = Archive::new(archive);
+    let mut entries = tar
+      .entries()
+      .map_err(|e| format!("Failed to read tar archive: {:?}", e))?;
+    while let Some(entry) = entries.next().await {
+      let mut entry = entry.map_err(|e| format!("Failed to read tar entry: {:?}", e))?;
+      let raw_path = entry
+        .path()
+        .map_err(|e| format!("Tar entry had an unreadable path: {:?}", e))?
+        .into_owned();
+      let path = normalize_entry_path(&raw_path)?;
+
+      let parent = path.parent().unwrap_or(Path::new("")).to_owned();
+      let parent_idx = ensure_dir_chain(&mut graph, &mut nodes, root, &parent);
+
+      let entry_type = entry.header().entry_type();
+      let node_idx = match entry_type {
+        TarEntryType::Directory => *nodes
+          .entry(path.clone())
+          .or_insert_with(|| graph.add_node((path.clone(), Node::Dir))),
+        TarEntryType::Symlink => {
+          let target = entry
+            .link_name()
+            .map_err(|e| format!("Unreadable symlink target for {:?}: {:?}", path, e))?
+            .ok_or_else(|| format!("Symlink entry {:?} had no target", path))?
+            .into_owned();
+          upsert_node(&mut graph, &mut nodes, &path, Node::Symlink(target))
+        }
+        TarEntryType::Regular => {
+          let size = entry.header().size().unwrap_or(0);
+          let permit = semaphore
+            .clone()
+            .acquire_many_owned(size.min(u64::from(MAX_IN_FLIGHT_BYTES)) as u32)
+            .await
+            .map_err(|e| format!("Upload semaphore was closed: {:?}", e))?;
+          let digest = if size <= BUFFER_THRESHOLD_BYTES {
+            let mut buf = BytesMut::with_capacity(size as usize);
+            while (buf.len() as u64) < size {
+              let n = entry
+                .read_buf(&mut buf)
+                .await
+                .map_err(|e| format!("Failed to read tar entry {:?}: {:?}", path, e))?;
+              if n == 0 {
+                return Err(format!(
+                  "Tar entry {:?} ended after {} of its {} declared bytes",
+                  path,
+                  buf.len(),
+                  size
+                ));
+              }
+            }
+            let bytes = buf.freeze();
+            // The digest only depends on the bytes, so it's known immediately and can be placed
+            // into the parent `Directory` proto right away; the background task below is purely
+            // responsible for getting those same bytes into the store before `ingest_tar` returns.
+            let digest = Digest::of_bytes(&bytes);
+            let store = self.clone();
+            let path_for_err = path.clone();
+            uploads.spawn(async move {
+              let _permit = permit;
+              store
+                .store_file_bytes(bytes)
+                .await
+                .map(|_| ())
+                .map_err(|e| format!("Failed to upload {:?}: {:?}", path_for_err, e))
+            });
+            digest
+          } else {
+            hash_streaming(self, &mut entry, &path, permit).await?
+          };
+          upsert_node(&mut graph, &mut nodes, &path, Node::File(digest))
+        }
+        other => return Err(format!("Unsupported tar entry type {:?} at {:?}", other, path)),
+      };
+      // `upsert_node` (and the `Directory` branch above) both reuse an existing node's index for
+      // a path already seen, so this is a no-op the second time a duplicate path's parent edge
+      // would otherwise be added twice.
+      if graph.find_edge(parent_idx, node_idx).is_none() {
+        graph.add_edge(parent_idx, node_idx, ());
+      }
+    }
+
+    while let Some(result) = uploads.join_next().await {
+      result.map_err(|e| format!("Upload task panicked: {:?}", e))??;
+    }
+
+    let mut stored: HashMap<NodeIndex, Digest> = HashMap::new();
+    let mut dfs = DfsPostOrder::new(&graph, root);
+    while let Some(idx) = dfs.next(&graph) {
+      let (_, node) = &graph[idx];
+      let digest = match node {
+        Node::File(digest) => *digest,
+        Node::Symlink(_) => continue,
+        Node::Dir => {
+          let mut directory = Directory::new();
+          let mut files = Vec::new();
+          let mut dirs = Vec::new();
+          let mut symlinks = Vec::new();
+          for child in graph.neighbors_directed(idx, Direction::Outgoing) {
+            let (child_path, child_node) = &graph[child];
+            let name = child_path
+              .file_name()
+              .map(|n| n.to_string_lossy().into_owned())
+              .unwrap_or_default();
+            match child_node {
+              Node::Dir => {
+                let mut node = DirectoryNode::new();
+                node.set_name(name);
+                node.set_digest(stored[&child].into());
+                dirs.push(node);
+              }
+              Node::File(digest) => {
+                let mut node = FileNode::new();
+                node.set_name(name);
+                node.set_digest((*digest).into());
+                files.push(node);
+              }
+              Node::Symlink(target) => {
+                let mut node = SymlinkNode::new();
+                node.set_name(name);
+                node.set_target(target.to_string_lossy().into_owned());
+                symlinks.push(node);
+              }
+            }
+          }
+          files.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+          dirs.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+          symlinks.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+          directory.set_files(files.into());
+          directory.set_directories(dirs.into());
+          directory.set_symlinks(symlinks.into());
+          // Two directories with identical contents serialize to identical bytes and therefore
+          // hash to the same `Digest`, so storing them here is where "dedupe identical directory
+          // protos" falls out for free -- no explicit cache is needed.
+          self.record_directory(&directory).await?
+        }
+      };
+      stored.insert(idx, digest);
+    }
+
+    Ok(stored[&root])
+  }
+}
+
+/// Rejects absolute paths and `..` components up front: either would let an archive write
+/// outside of the tree it's nominally being ingested into.
+fn normalize_entry_path(path: &Path) -> Result<PathBuf, String> {
+  let mut normalized = PathBuf::new();
+  for component in path.components() {
+    match component {
+      Component::Normal(part) => normalized.push(part),
+      Component::CurDir => continue,
+      Component::ParentDir => {
+        return Err(format!(
+          "Refusing to ingest tar entry with a `..` component: {:?}",
+          path
+        ))
+      }
+      Component::RootDir | Component::Prefix(_) => {
+        return Err(format!(
+          "Refusing to ingest tar entry with an absolute path: {:?}",
+          path
+        ))
+      }
+    }
+  }
+  Ok(normalized)
+}
+
+/// Records `node` at `path`, reusing the existing graph node if the archive already has an entry
+/// at that path (a tar archive -- unlike a `Directory` proto -- is free to list the same path more
+/// than once, e.g. a file overwritten later in the stream) rather than adding a second, duplicate
+/// node: the last entry at a given path wins, matching what unpacking the same archive to disk
+/// with `tar`(1) would leave behind.
+fn upsert_node(
+  graph: &mut DiGraph<(PathBuf, Node), ()>,
+  nodes: &mut HashMap<PathBuf, NodeIndex>,
+  path: &Path,
+  node: Node,
+) -> NodeIndex {
+  match nodes.get(path) {
+    Some(&idx) => {
+      graph[idx] = (path.to_owned(), node);
+      idx
+    }
+    None => {
+      let idx = graph.add_node((path.to_owned(), node));
+      nodes.insert(path.to_owned(), idx);
+      idx
+    }
+  }
+}
+
+/// Walks up from `dir` to the first ancestor already present in `nodes` (at worst, the
+/// already-present root), synthesizing and linking any `Directory` nodes the archive itself never
+/// listed an entry for.
+fn ensure_dir_chain(
+  graph: &mut DiGraph<(PathBuf, Node), ()>,
+  nodes: &mut HashMap<PathBuf, NodeIndex>,
+  root: NodeIndex,
+  dir: &Path,
+) -> NodeIndex {
+  if let Some(idx) = nodes.get(dir) {
+    return *idx;
+  }
+  let parent = dir.parent().unwrap_or(Path::new(""));
+  let parent_idx = if parent == dir {
+    root
+  } else {
+    ensure_dir_chain(graph, nodes, root, parent)
+  };
+  let idx = graph.add_node((dir.to_owned(), Node::Dir));
+  graph.add_edge(parent_idx, idx, ());
+  nodes.insert(dir.to_owned(), idx);
+  idx
+}
+
+/// Hands a large entry to `Store::store_file_bytes_streamed`. With a backend that overrides
+/// `ByteStore::store_bytes_streamed` (as `LocalByteStore` does), the hash is computed
+/// incrementally as each chunk is read off of `entry`, so the entry is never held in memory
+/// whole; the trait's default implementation, which the in-memory backends use, reads the whole
+/// entry into one buffer before hashing it.
+async fn hash_streaming<R: AsyncRead + Unpin>(
+  store: &Store,
+  entry: &mut R,
+  path: &Path,
+  _permit: tokio::sync::OwnedSemaphorePermit,
+) -> Result<Digest, String> {
+  store
+    .store_file_bytes_streamed(entry)
+    .await
+    .map_err(|e| format!("Failed to stream tar entry {:?} into the store: {:?}", path, e))
+}
+
@@ -25,8 +25,6 @@
 // Arc<Mutex> can be more clear than needing to grok Orderings:
 #![allow(clippy::mutex_atomic)]
 
-use bindgen;
-
 use std::env;
 use std::io;
 use std::path::{Path, PathBuf};
@@ -41,35 +39,10 @@ fn pushd<T, F: FnOnce() -> T, P: AsRef<Path>>(path: P, f: F) -> io::Result<T> {
 }
 
 fn main() {
-  let bindings = PathBuf::from("src/mmap_bindings.rs");
-
-  /* FIXME: why can't bindgen figure this out itself??? */
-  let base = PathBuf::from("/Library/Developer/CommandLineTools/SDKs/MacOSX.sdk/usr/include");
-
-  /* NB: Exporting all the functions and variables necessary for this gist:
-   * https://gist.github.com/garcia556/8231e844a90457c99cc72e5add8388e4! */
-  bindgen::builder()
-    .whitelist_function("shm.*")
-    .whitelist_function("m.*map")
-    .whitelist_var("O_.*")
-    .whitelist_var("S_.*")
-    .whitelist_var("PROT_.*")
-    .whitelist_var("SHM.*")
-    .whitelist_var("MAP_.*")
-    .whitelist_var("IPC_.*")
-    .header(base.join("sys/ipc.h").to_str().unwrap())
-    .header(base.join("sys/shm.h").to_str().unwrap())
-    .header(base.join("stdio.h").to_str().unwrap())
-    .header(base.join("fcntl.h").to_str().unwrap())
-    .header(base.join("unistd.h").to_str().unwrap())
-    .raw_line("#![allow(non_camel_case_types)]")
-    .raw_line("#![allow(non_upper_case_globals)]")
-    .raw_line("#![allow(non_snake_case)]")
-    .raw_line("#![allow(dead_code)]")
-    .generate()
-    .unwrap()
-    .write_to_file(bindings)
-    .unwrap();
+  /* NB: The SHM backend now talks to the POSIX shm_open(3)/mmap(2) APIs via the `libc` crate
+   * directly (see src/shm.rs), rather than the System V shmget/shmat bindings that used to be
+   * bindgen'd from a macOS-only SDK header path here. libc already ships those bindings for every
+   * platform we support, so there's nothing left for this build script to generate. */
 
   /* Compile some thrift! */
   let crate_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
@@ -83,15 +56,18 @@ fn main() {
       .arg("pants_vcfs_interface.thrift")
       .output()
       .unwrap();
-    Command::new("sed")
+    // NB: `sed -i` itself isn't used here because GNU and BSD sed take incompatible syntax for an
+    // empty backup suffix (`-i ""` as two args vs. `-i''` attached) -- writing `sed`'s stdout back
+    // over the file by hand sidesteps the difference and works identically on both.
+    let output = Command::new("sed")
       .arg("-nE")
-      .args(&["-i", ""])
       .args(&["-e", "p"])
       .args(&["-e", "3 a#![allow(deprecated)]"])
       .args(&["-e", "3 a#![allow(ambiguous_associated_items)]"])
       .arg("pants_vcfs_interface.rs")
       .output()
       .unwrap();
+    std::fs::write("pants_vcfs_interface.rs", output.stdout).unwrap();
   })
   .unwrap();
 }
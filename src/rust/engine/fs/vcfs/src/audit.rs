@@ -0,0 +1,96 @@
+use serde::Serialize;
+
+use parking_lot::Mutex;
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub enum AuditStatus {
+  Ok,
+  Error,
+}
+
+/// A structured record of a single `expand_globs` RPC, suitable for replacing the ad-hoc
+/// `eprintln!`s that used to be scattered through `do_expand_globs_call`.
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditEvent {
+  pub message_id: i64,
+  pub include_patterns: Vec<String>,
+  pub exclude_patterns: Vec<String>,
+  pub strict_match_behavior: String,
+  pub conjunction: String,
+  pub status: AuditStatus,
+  pub error_text: Option<String>,
+  pub num_files: usize,
+  pub bytes_read: u64,
+  pub latency_millis: u128,
+}
+
+/// Somewhere a completed `AuditEvent` can be recorded. Implementations must be cheap to call from
+/// the hot path of every RPC -- e.g. buffering or sending to a background thread, rather than
+/// blocking on a slow sink.
+pub trait AuditSink: Send + Sync {
+  fn record(&self, event: AuditEvent);
+}
+
+/// The default sink: drops every event on the floor. Used when `VcfsInstance::new` is not given
+/// an explicit sink.
+pub struct NoopAuditSink;
+
+impl AuditSink for NoopAuditSink {
+  fn record(&self, _event: AuditEvent) {}
+}
+
+/// Buffers events in memory, so tests can assert on exactly what was recorded.
+#[derive(Default)]
+pub struct InMemoryAuditSink {
+  events: Mutex<Vec<AuditEvent>>,
+}
+
+impl InMemoryAuditSink {
+  pub fn new() -> Self {
+    InMemoryAuditSink {
+      events: Mutex::new(Vec::new()),
+    }
+  }
+
+  pub fn events(&self) -> Vec<AuditEvent> {
+    self.events.lock().clone()
+  }
+}
+
+impl AuditSink for InMemoryAuditSink {
+  fn record(&self, event: AuditEvent) {
+    self.events.lock().push(event);
+  }
+}
+
+/// Appends one JSON object per event to a file, so operators can query what globs were expanded
+/// after the fact (e.g. with `jq`), or tail the file to forward events to telemetry.
+pub struct JsonLinesFileAuditSink {
+  file: Mutex<File>,
+}
+
+impl JsonLinesFileAuditSink {
+  pub fn new<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(JsonLinesFileAuditSink {
+      file: Mutex::new(file),
+    })
+  }
+}
+
+impl AuditSink for JsonLinesFileAuditSink {
+  fn record(&self, event: AuditEvent) {
+    match serde_json::to_string(&event) {
+      Ok(line) => {
+        if let Err(e) = writeln!(self.file.lock(), "{}", line) {
+          eprintln!("failed to write audit event to file: {:?}", e);
+        }
+      }
+      Err(e) => eprintln!("failed to serialize audit event {:?}: {:?}", event, e),
+    }
+  }
+}
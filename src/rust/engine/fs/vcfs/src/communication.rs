@@ -1,10 +1,11 @@
 use super::pants_vcfs_interface::{self, TVcfsServerSyncClient};
 
+use native_tls::{TlsConnector, TlsStream};
 use parking_lot::Mutex;
 use thrift::protocol::{TBinaryInputProtocol, TBinaryOutputProtocol};
 
 use std::io;
-use std::net::Shutdown;
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
 use std::ops::{Deref, DerefMut};
 use std::os::unix::net::UnixStream;
 use std::path::{Path, PathBuf};
@@ -15,24 +16,118 @@ pub enum CommunicationError {
   S(String),
 }
 
+/// The decoded, non-envelope contents of a successful `expandGlobs` RPC: the files it found, plus
+/// the names of any SHM regions the caller hasn't mapped yet and will need to before reading the
+/// file contents those descriptors point into.
+#[derive(Debug)]
+pub struct ExpandGlobsResult {
+  pub files: Vec<pants_vcfs_interface::FileWithContentsDescriptor>,
+  pub new_shm_segments: Vec<String>,
+}
+
+impl From<io::Error> for CommunicationError {
+  fn from(err: io::Error) -> Self {
+    CommunicationError::S(format!("io error: {:?}", err))
+  }
+}
+
+impl From<native_tls::Error> for CommunicationError {
+  fn from(err: native_tls::Error) -> Self {
+    CommunicationError::S(format!("tls error: {:?}", err))
+  }
+}
+
+/// A duplex byte stream that a `VcfsClient` can speak Thrift's binary protocol over.
+///
+/// This is implemented for the unix domain socket, plain TCP, and TLS-over-TCP backends below, so
+/// the rest of this module never needs to know which concrete transport it was handed.
+pub trait VcfsTransport: io::Read + io::Write + Send {
+  /// Shut down both halves of the transport. Called from `Drop` so that the VCFS daemon
+  /// immediately observes the client going away, instead of waiting on a read timeout.
+  fn close(&mut self) -> io::Result<()>;
+}
+
+impl VcfsTransport for UnixStream {
+  fn close(&mut self) -> io::Result<()> {
+    self.shutdown(Shutdown::Both)
+  }
+}
+
+impl VcfsTransport for TcpStream {
+  fn close(&mut self) -> io::Result<()> {
+    self.shutdown(Shutdown::Both)
+  }
+}
+
+impl VcfsTransport for TlsStream<TcpStream> {
+  fn close(&mut self) -> io::Result<()> {
+    /* A cleanly-closed TLS stream sends a close_notify alert before shutting down the
+     * underlying TCP connection, so the server doesn't mistake this for a truncation attack. */
+    match self.shutdown() {
+      Ok(()) => Ok(()),
+      Err(e) => Err(e),
+    }
+  }
+}
+
+/// Where a `VcfsInstance` should dial out to find the VCFS daemon.
+#[derive(Clone, Debug)]
+pub enum TransportSpec {
+  UnixSocket(PathBuf),
+  Tcp(String),
+  Tls(String),
+}
+
 pub struct Socket {
-  pub path: PathBuf,
-  pub stream: UnixStream,
+  pub description: String,
+  pub stream: Box<dyn VcfsTransport>,
 }
 
 impl Socket {
-  pub fn create<P: AsRef<Path>>(path: &P) -> io::Result<Self> {
+  pub fn from_unix_socket_path<P: AsRef<Path>>(path: &P) -> io::Result<Self> {
     let stream = UnixStream::connect(path)?;
     Ok(Socket {
-      path: path.as_ref().to_path_buf(),
-      stream,
+      description: format!("unix:{}", path.as_ref().display()),
+      stream: Box::new(stream),
     })
   }
+
+  pub fn from_tcp_addr<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+    let stream = TcpStream::connect(addr)?;
+    Ok(Socket {
+      description: format!("tcp:{:?}", stream.peer_addr()?),
+      stream: Box::new(stream),
+    })
+  }
+
+  pub fn from_tls_addr<A: ToSocketAddrs>(addr: A, domain: &str) -> Result<Self, CommunicationError> {
+    let tcp_stream = TcpStream::connect(addr)?;
+    let connector = TlsConnector::new()?;
+    let tls_stream = connector.connect(domain, tcp_stream)?;
+    Ok(Socket {
+      description: format!("tls:{}", domain),
+      stream: Box::new(tls_stream),
+    })
+  }
+
+  pub fn from_spec(spec: &TransportSpec) -> Result<Self, CommunicationError> {
+    match spec {
+      TransportSpec::UnixSocket(path) => Ok(Self::from_unix_socket_path(path)?),
+      TransportSpec::Tcp(addr) => Ok(Self::from_tcp_addr(addr)?),
+      TransportSpec::Tls(addr) => {
+        /* NB: We connect to `addr` as a socket address, but verify the server's certificate
+         * against the hostname portion, mirroring how most TLS clients split "where to dial"
+         * from "who we expect to be speaking to". */
+        let domain = addr.rsplitn(2, ':').last().unwrap_or(addr);
+        Self::from_tls_addr(addr, domain)
+      }
+    }
+  }
 }
 
 impl Drop for Socket {
   fn drop(&mut self) {
-    self.stream.shutdown(Shutdown::Both).unwrap();
+    self.stream.close().unwrap();
   }
 }
 
@@ -110,15 +205,29 @@ impl VcfsClient {
   }
 
   pub fn from_socket_path<P: AsRef<Path>>(p: &P) -> io::Result<Self> {
-    let socket = Socket::create(p)?;
+    let socket = Socket::from_unix_socket_path(p)?;
+    Ok(Self::from_socket(socket))
+  }
+
+  pub fn from_tcp_addr<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+    let socket = Socket::from_tcp_addr(addr)?;
+    Ok(Self::from_socket(socket))
+  }
+
+  pub fn from_tls_addr<A: ToSocketAddrs>(addr: A, domain: &str) -> Result<Self, CommunicationError> {
+    let socket = Socket::from_tls_addr(addr, domain)?;
+    Ok(Self::from_socket(socket))
+  }
+
+  pub fn from_transport_spec(spec: &TransportSpec) -> Result<Self, CommunicationError> {
+    let socket = Socket::from_spec(spec)?;
     Ok(Self::from_socket(socket))
   }
 
   pub fn do_expand_globs_call(
     &mut self,
     expand_globs_message: pants_vcfs_interface::ExpandGlobsMessage,
-  ) -> Result<Vec<pants_vcfs_interface::FileWithContentsDescriptor>, CommunicationError> {
-    eprintln!("message was: {:?}", &expand_globs_message);
+  ) -> Result<ExpandGlobsResult, CommunicationError> {
     match self.expand_globs(expand_globs_message.clone()) {
       Err(e) => Err(CommunicationError::S(format!(
         "error expanding globs from message {:?}: {:?}",
@@ -133,6 +242,7 @@ impl VcfsClient {
         status: Some(status),
         all_files,
         error_text,
+        new_shm_segments,
         ..
       }) => match status {
         pants_vcfs_interface::GlobExpansionResultCode::Error => {
@@ -146,9 +256,10 @@ impl VcfsClient {
             message_id,
             expand_globs_message.message_id.unwrap().id.unwrap()
           );
-          eprintln!("all_files was: {:?}", all_files);
-          let all_files = all_files.unwrap_or_else(Vec::new);
-          Ok(all_files)
+          Ok(ExpandGlobsResult {
+            files: all_files.unwrap_or_else(Vec::new),
+            new_shm_segments: new_shm_segments.unwrap_or_else(Vec::new),
+          })
         }
       },
       Ok(x) => unimplemented!(
@@ -158,4 +269,18 @@ impl VcfsClient {
       ),
     }
   }
+
+  pub fn do_cancel_call(&mut self, message_id: i64) -> Result<(), CommunicationError> {
+    let cancel_message = pants_vcfs_interface::CancelMessage {
+      message_id: Some(pants_vcfs_interface::MessageId {
+        id: Some(message_id),
+      }),
+    };
+    self.cancel(cancel_message).map_err(|e| {
+      CommunicationError::S(format!(
+        "error cancelling message id {:?}: {:?}",
+        message_id, e
+      ))
+    })
+  }
 }
@@ -25,26 +25,31 @@
 // Arc<Mutex> can be more clear than needing to grok Orderings:
 #![allow(clippy::mutex_atomic)]
 
+mod audit;
 mod communication;
-mod mmap_bindings;
 mod pants_vcfs_interface;
 mod shm;
 
-use ::fs::{File, GlobExpansionConjunction, PathGlobs, PathStat, StrictGlobMatching};
+pub use audit::{AuditEvent, AuditSink, AuditStatus, InMemoryAuditSink, JsonLinesFileAuditSink};
 
+use ::fs::{Conjunction, File, PathGlobs, PathStat, Store, StrictGlobMatching};
+
+use bytes::Bytes;
 use futures01::{future, Future};
-use parking_lot::{Mutex, RwLock};
+use parking_lot::Mutex;
+use tokio_timer::Timeout;
 
+use std::collections::HashSet;
 use std::env;
 use std::fs;
 use std::io;
-use std::ops::Deref;
 use std::path::PathBuf;
 use std::str;
 use std::sync::{
   atomic::{AtomicU32, Ordering},
   Arc,
 };
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub enum VcfsError {
@@ -87,40 +92,70 @@ impl From<communication::CommunicationError> for VcfsError {
   }
 }
 
-#[derive(Clone)]
-pub struct ShmHandleWrapper {
-  shm_handle: Arc<RwLock<shm::ShmHandle>>,
-}
+/// The name of the SHM region that every `VcfsInstance` maps eagerly at startup. Additional
+/// regions are negotiated lazily as the daemon references them in `new_shm_segments`.
+const INITIAL_SHM_SEGMENT_NAME: &str = "/pants-vcfs-daemon-0";
 
-impl ShmHandleWrapper {
-  pub fn new(shm_handle: shm::ShmHandle) -> Self {
-    ShmHandleWrapper {
-      shm_handle: Arc::new(RwLock::new(shm_handle)),
-    }
-  }
+/// The fixed size, in bytes, of every individual SHM region. A glob expansion whose file content
+/// exceeds this is spread across multiple regions by the daemon, rather than requiring one
+/// unboundedly large mapping.
+const SHM_SEGMENT_SIZE_BYTES: usize = 4096 * 500_000;
+
+pub struct VcfsInstance {
+  message_id_counter: AtomicU32,
+  executor: task_executor::Executor,
+  vcfs_client: Arc<Mutex<communication::VcfsClient>>,
+  shm_regions: Arc<shm::ShmRegionSet>,
+  store: Store,
+  /* Message ids for `expand_globs` calls which have not yet completed, so that a dropped future
+   * or an elapsed deadline can tell the daemon to stop working on them instead of leaving it to
+   * discover that on its own. */
+  outstanding_message_ids: Arc<Mutex<HashSet<i64>>>,
+  audit_sink: Arc<dyn audit::AuditSink>,
 }
 
-impl Deref for ShmHandleWrapper {
-  type Target = Arc<RwLock<shm::ShmHandle>>;
+/// Removes `message_id` from `outstanding_message_ids` when dropped, sending a `CancelMessage` to
+/// the daemon if the call it was tracking never got the chance to clean up after itself -- i.e.
+/// the future was dropped before completion, whether from an elapsed deadline or the caller
+/// simply losing interest.
+struct OutstandingCallGuard {
+  message_id: i64,
+  vcfs_client: Arc<Mutex<communication::VcfsClient>>,
+  outstanding_message_ids: Arc<Mutex<HashSet<i64>>>,
+  completed: bool,
+}
 
-  fn deref(&self) -> &Self::Target {
-    &self.shm_handle
+impl OutstandingCallGuard {
+  fn complete(mut self) {
+    self.completed = true;
   }
 }
 
-/* TODO: why doesn't Arc<RwLock<>> do this for us automatically?? */
-unsafe impl Send for ShmHandleWrapper {}
-unsafe impl Sync for ShmHandleWrapper {}
-
-pub struct VcfsInstance {
-  message_id_counter: AtomicU32,
-  executor: task_executor::Executor,
-  vcfs_client: Arc<Mutex<communication::VcfsClient>>,
-  shm_handle: ShmHandleWrapper,
+impl Drop for OutstandingCallGuard {
+  fn drop(&mut self) {
+    let was_outstanding = self
+      .outstanding_message_ids
+      .lock()
+      .remove(&self.message_id);
+    if was_outstanding && !self.completed {
+      if let Err(e) = self.vcfs_client.lock().do_cancel_call(self.message_id) {
+        eprintln!(
+          "failed to cancel message id {:?} on drop: {:?}",
+          self.message_id, e
+        );
+      }
+    }
+  }
 }
 
 impl VcfsInstance {
-  pub fn new(executor: task_executor::Executor, root: PathBuf) -> Result<Self, VcfsError> {
+  pub fn new(
+    executor: task_executor::Executor,
+    root: PathBuf,
+    transport: communication::TransportSpec,
+    store: Store,
+    audit_sink: Option<Arc<dyn audit::AuditSink>>,
+  ) -> Result<Self, VcfsError> {
     /* Assert that the given git root is an existing directory. */
     assert!(fs::metadata(&root)?.is_dir());
 
@@ -132,46 +167,44 @@ impl VcfsInstance {
 
     let message_id_counter = AtomicU32::new(0);
 
-    /* let socket_path = root.join(".vcfs-socket="); */
-    let socket_path = PathBuf::from("/Users/dmcclanahan/workspace/.vcfs-socket");
-    let vcfs_client = communication::VcfsClient::from_socket_path(&socket_path)?;
+    let vcfs_client = communication::VcfsClient::from_transport_spec(&transport)?;
 
-    let shm_handle = shm::ShmHandle::new(
-      1_000_003 as mmap_bindings::key_t,
-      (4096 * 500000) as usize,
-      shm::Permission::Read,
-    )?;
+    let shm_regions = shm::ShmRegionSet::new(SHM_SEGMENT_SIZE_BYTES, shm::Permission::Read);
+    /* Eagerly map the region every daemon is guaranteed to use for the first segment of any
+     * response; everything past that is mapped lazily as expand_globs calls reference it. */
+    shm_regions.region(INITIAL_SHM_SEGMENT_NAME)?;
 
     Ok(VcfsInstance {
       message_id_counter,
       executor,
       vcfs_client: Arc::new(Mutex::new(vcfs_client)),
-      shm_handle: ShmHandleWrapper::new(shm_handle),
+      shm_regions: Arc::new(shm_regions),
+      store,
+      outstanding_message_ids: Arc::new(Mutex::new(HashSet::new())),
+      audit_sink: audit_sink.unwrap_or_else(|| Arc::new(audit::NoopAuditSink)),
     })
   }
 
   fn extract_thrift_path_globs(path_globs: PathGlobs) -> pants_vcfs_interface::PathGlobs {
-    let PathGlobs {
-      exclude,
-      strict_match_behavior,
-      conjunction,
-      patterns,
-      ..
-    } = path_globs;
-    let include_patterns: Vec<String> = patterns
-      .into_iter()
-      .map(|p| p.as_str().to_string())
+    let include_patterns: Vec<String> = path_globs
+      .include()
+      .iter()
+      .map(|entry| entry.input.as_str().to_string())
       .collect();
-    let exclude_patterns: Vec<String> = exclude.exclude_patterns().into_iter().cloned().collect();
-    let strictness = match strict_match_behavior {
-      /* TODO: make use of the string provided for Error and Warn! */
-      StrictGlobMatching::Error(_) => pants_vcfs_interface::StrictGlobMatching::Error,
-      StrictGlobMatching::Warn(_) => pants_vcfs_interface::StrictGlobMatching::Warn,
+    let exclude_patterns: Vec<String> = path_globs
+      .exclude()
+      .exclude_patterns()
+      .iter()
+      .cloned()
+      .collect();
+    let strictness = match path_globs.strict_match_behavior() {
+      StrictGlobMatching::Error => pants_vcfs_interface::StrictGlobMatching::Error,
+      StrictGlobMatching::Warn => pants_vcfs_interface::StrictGlobMatching::Warn,
       StrictGlobMatching::Ignore => pants_vcfs_interface::StrictGlobMatching::Ignore,
     };
-    let conjunction = match conjunction {
-      GlobExpansionConjunction::AllMatch => pants_vcfs_interface::Conjunction::AllMatch,
-      GlobExpansionConjunction::AnyMatch => pants_vcfs_interface::Conjunction::AnyMatch,
+    let conjunction = match path_globs.conjunction() {
+      Conjunction::And => pants_vcfs_interface::Conjunction::AllMatch,
+      Conjunction::Or => pants_vcfs_interface::Conjunction::AnyMatch,
     };
     pants_vcfs_interface::PathGlobs {
       include_patterns: Some(include_patterns),
@@ -184,17 +217,18 @@ impl VcfsInstance {
   fn create_expand_globs_message(
     &self,
     path_globs: pants_vcfs_interface::PathGlobs,
-  ) -> pants_vcfs_interface::ExpandGlobsMessage {
+  ) -> (i64, pants_vcfs_interface::ExpandGlobsMessage) {
     /* Get a unique id for the message. */
-    let id = self.message_id_counter.fetch_add(1, Ordering::Relaxed);
+    let id = self.message_id_counter.fetch_add(1, Ordering::Relaxed) as i64;
     let message_id = pants_vcfs_interface::MessageId {
       /* A u32 can cleanly downnsize into an i64! */
-      id: Some(id as i64),
+      id: Some(id),
     };
-    pants_vcfs_interface::ExpandGlobsMessage {
+    let message = pants_vcfs_interface::ExpandGlobsMessage {
       message_id: Some(message_id),
       path_globs: Some(path_globs),
-    }
+    };
+    (id, message)
   }
 
   pub fn expand_globs(
@@ -202,11 +236,26 @@ impl VcfsInstance {
     path_globs: PathGlobs,
   ) -> impl Future<Item = Vec<PathStat>, Error = VcfsError> {
     let vcfs_client = self.vcfs_client.clone();
-    let shm_handle = self.shm_handle.clone();
+    let shm_regions = self.shm_regions.clone();
+    let store = self.store.clone();
+    let audit_sink = self.audit_sink.clone();
 
     let thrift_globs = Self::extract_thrift_path_globs(path_globs);
-    let expand_globs_message = self.create_expand_globs_message(thrift_globs);
+    let (message_id, expand_globs_message) = self.create_expand_globs_message(thrift_globs);
+    let path_globs_for_audit = expand_globs_message
+      .path_globs
+      .clone()
+      .expect("path_globs is always set by create_expand_globs_message");
+
+    self.outstanding_message_ids.lock().insert(message_id);
+    let mut guard = OutstandingCallGuard {
+      message_id,
+      vcfs_client: vcfs_client.clone(),
+      outstanding_message_ids: self.outstanding_message_ids.clone(),
+      completed: false,
+    };
 
+    let started_at = Instant::now();
     let glob_expansion = future::lazy(move || {
       future::result(
         vcfs_client
@@ -215,33 +264,127 @@ impl VcfsInstance {
       )
     });
 
-    let all_file_path_stats = glob_expansion.map(
-      move |file_descriptors: Vec<pants_vcfs_interface::FileWithContentsDescriptor>| {
+    let audited_expansion = glob_expansion.then(move |result| {
+      let (status, error_text, num_files, bytes_read) = match &result {
+        Ok(expand_result) => {
+          let bytes_read: u64 = expand_result
+            .files
+            .iter()
+            .map(|fd| {
+              let start = fd.contents_start.unwrap_or(0);
+              let end = fd.contents_end.unwrap_or(start);
+              (end - start) as u64
+            })
+            .sum();
+          (
+            audit::AuditStatus::Ok,
+            None,
+            expand_result.files.len(),
+            bytes_read,
+          )
+        }
+        Err(e) => (audit::AuditStatus::Error, Some(format!("{:?}", e)), 0, 0),
+      };
+      audit_sink.record(audit::AuditEvent {
+        message_id,
+        include_patterns: path_globs_for_audit.include_patterns.unwrap_or_default(),
+        exclude_patterns: path_globs_for_audit.exclude_patterns.unwrap_or_default(),
+        strict_match_behavior: format!("{:?}", path_globs_for_audit.strictness),
+        conjunction: format!("{:?}", path_globs_for_audit.conjunction),
+        status,
+        error_text,
+        num_files,
+        bytes_read,
+        latency_millis: started_at.elapsed().as_millis(),
+      });
+      result
+    });
+
+    let ingestions = audited_expansion.map_err(VcfsError::from).and_then(
+      move |expand_result: communication::ExpandGlobsResult| -> Result<_, VcfsError> {
+        /* Negotiate any regions the daemon told us about up front, so a file descriptor pointing
+         * into a region we haven't opened yet never has to block the first file it's needed for. */
+        for segment_name in &expand_result.new_shm_segments {
+          shm_regions.region(segment_name)?;
+        }
+
         /* TODO: parallelize/SIMDify this (with rayon??)?? see SIMDify crate? */
-        let shm_handle = (*shm_handle).read();
-        let file_contents: Vec<PathStat> = file_descriptors
+        let ingestions: Vec<_> = expand_result
+          .files
           .into_iter()
           .map(|fd| {
-            let shm::FileWithContents { path, .. } =
-              shm::FileWithContents::from_shm_descriptor(&*shm_handle, &fd);
-            /* FIXME: read the file contents into the Store too!!! */
-            PathStat::File {
-              path: path.to_path_buf(),
-              stat: File {
-                path: path.to_path_buf(),
-                is_executable: false,
-              },
-            }
+            let segment_name = fd
+              .segment_name
+              .clone()
+              .unwrap_or_else(|| INITIAL_SHM_SEGMENT_NAME.to_string());
+            let region = shm_regions.region(&segment_name)?;
+            let shm::FileWithContents {
+              path,
+              contents,
+              metadata,
+            } = shm::FileWithContents::from_shm_descriptor(&region, &fd)?;
+            let path = path.to_path_buf();
+            /* The SHM region is read-only and already mmap'd, so the only copy we pay for is the
+             * one `Store::store_file_bytes` needs to take ownership of the content -- there's no
+             * intermediate round trip through a temp file the way ingesting from a real
+             * filesystem path would require. */
+            let content = Bytes::copy_from_slice(contents);
+            Ok(
+              store
+                .store_file_bytes(content, true)
+                .map(move |_digest| PathStat::File {
+                  path: path.clone(),
+                  stat: File {
+                    path,
+                    is_executable: metadata.is_executable,
+                  },
+                })
+                .map_err(VcfsError::S),
+            )
           })
-          .collect();
-        file_contents
+          .collect::<Result<Vec<_>, VcfsError>>()?;
+        Ok(ingestions)
       },
     );
 
+    let all_file_path_stats = ingestions.and_then(|ingestions| future::join_all(ingestions));
+
     self
       .executor
       .spawn_on_io_pool(all_file_path_stats)
       .map_err(|e| e.into())
+      .then(move |result| {
+        /* The call ran to completion (successfully or not) without being dropped or timing out,
+         * so there's nothing left for the daemon to cancel. */
+        guard.complete();
+        result
+      })
+  }
+
+  ///
+  /// As `expand_globs`, but gives up and cancels the in-flight RPC if `deadline` elapses first.
+  ///
+  pub fn expand_globs_with_deadline(
+    &self,
+    path_globs: PathGlobs,
+    deadline: Duration,
+  ) -> impl Future<Item = Vec<PathStat>, Error = VcfsError> {
+    Timeout::new(self.expand_globs(path_globs), deadline).map_err(|timeout_err| {
+      timeout_err
+        .into_inner()
+        .unwrap_or_else(|| VcfsError::S("expand_globs call timed out".to_string()))
+    })
+  }
+
+  ///
+  /// Ask the daemon to stop working on the `expand_globs` call identified by `message_id`, if it
+  /// is still outstanding. A no-op if the call already completed.
+  ///
+  pub fn cancel(&self, message_id: i64) -> Result<(), VcfsError> {
+    if self.outstanding_message_ids.lock().remove(&message_id) {
+      self.vcfs_client.lock().do_cancel_call(message_id)?;
+    }
+    Ok(())
   }
 }
 
@@ -1,21 +1,21 @@
-/* From https://gist.github.com/garcia556/8231e844a90457c99cc72e5add8388e4!! */
-
-use super::mmap_bindings::{self, key_t, size_t, IPC_CREAT, IPC_R, IPC_W, SHM_RDONLY};
 use super::pants_vcfs_interface;
 
-use std::ffi::{self, CStr, CString};
+use libc::{self, c_void, mode_t, off_t, size_t};
+use parking_lot::Mutex;
+
+use std::collections::HashMap;
+use std::ffi::{self, CString};
 use std::io;
-use std::mem;
 use std::ops::{Deref, DerefMut};
-use std::os::{self, unix::ffi::OsStrExt};
 use std::path::Path;
 use std::ptr;
 use std::slice;
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub enum ShmError {
   S(String),
-  NullTerminated(ffi::FromBytesWithNulError),
+  InvalidName(ffi::NulError),
 }
 
 impl From<String> for ShmError {
@@ -24,9 +24,9 @@ impl From<String> for ShmError {
   }
 }
 
-impl From<ffi::FromBytesWithNulError> for ShmError {
-  fn from(err: ffi::FromBytesWithNulError) -> Self {
-    ShmError::NullTerminated(err)
+impl From<ffi::NulError> for ShmError {
+  fn from(err: ffi::NulError) -> Self {
+    ShmError::InvalidName(err)
   }
 }
 
@@ -36,10 +36,15 @@ pub enum Permission {
   Write,
 }
 
+/// A POSIX shared memory region, opened with `shm_open(3)`/`mmap(2)` and named the same way a
+/// named pipe or unix socket would be -- e.g. `/pants-vcfs-0`. This replaces the previous System V
+/// `shmget`/`shmat` backend (bound to macOS-specific bindgen'd headers) with the POSIX API, which
+/// is implemented the same way by `libc` on both Linux and macOS.
 pub struct ShmHandle {
   size_bytes: usize,
-  shm_fd: os::raw::c_int,
-  mmap_addr: *mut os::raw::c_void,
+  shm_fd: libc::c_int,
+  mmap_addr: *mut c_void,
+  #[allow(dead_code)]
   permission: Permission,
 }
 
@@ -47,22 +52,95 @@ impl Deref for ShmHandle {
   type Target = [u8];
 
   fn deref(&self) -> &[u8] {
-    unsafe {
-      slice::from_raw_parts(
-        mem::transmute::<*mut os::raw::c_void, *const u8>(self.mmap_addr),
-        self.size_bytes,
-      )
-    }
+    unsafe { slice::from_raw_parts(self.mmap_addr as *const u8, self.size_bytes) }
   }
 }
 
 impl DerefMut for ShmHandle {
   fn deref_mut(&mut self) -> &mut [u8] {
-    unsafe {
-      slice::from_raw_parts_mut(
-        mem::transmute::<*mut os::raw::c_void, *mut u8>(self.mmap_addr),
-        self.size_bytes,
-      )
+    unsafe { slice::from_raw_parts_mut(self.mmap_addr as *mut u8, self.size_bytes) }
+  }
+}
+
+/* The mmap'd region is only ever read from (or, for a writer, written to) through the `Deref`
+ * impls above, which don't alias Rust-level references across threads in a way the raw pointer
+ * would otherwise make the compiler suspicious of. */
+unsafe impl Send for ShmHandle {}
+unsafe impl Sync for ShmHandle {}
+
+///
+/// A growable collection of named SHM regions, opened lazily as the daemon tells the client about
+/// them. This is what lets a single glob expansion whose total content exceeds the fixed size of
+/// one region spill over into additional, separately-negotiated regions instead of requiring one
+/// enormous up-front mapping.
+///
+pub struct ShmRegionSet {
+  region_size_bytes: usize,
+  permission: Permission,
+  regions: Mutex<HashMap<String, Arc<ShmHandle>>>,
+}
+
+impl ShmRegionSet {
+  pub fn new(region_size_bytes: usize, permission: Permission) -> Self {
+    ShmRegionSet {
+      region_size_bytes,
+      permission,
+      regions: Mutex::new(HashMap::new()),
+    }
+  }
+
+  ///
+  /// Returns the already-mapped region named `name`, opening and mapping it first if this is the
+  /// first time it's been referenced.
+  ///
+  pub fn region(&self, name: &str) -> Result<Arc<ShmHandle>, ShmError> {
+    if let Some(region) = self.regions.lock().get(name) {
+      return Ok(region.clone());
+    }
+    let handle = Arc::new(ShmHandle::new(name, self.region_size_bytes, self.permission)?);
+    Ok(
+      self
+        .regions
+        .lock()
+        .entry(name.to_string())
+        .or_insert(handle)
+        .clone(),
+    )
+  }
+}
+
+pub struct FileMetadata {
+  pub is_executable: bool,
+  pub size_bytes: u64,
+  /* Split into whole seconds and nanoseconds to match how `std::os::unix::fs::MetadataExt`
+   * exposes `st_mtime`/`st_mtime_nsec`, so two files modified within the same second can still
+   * be told apart for fingerprinting purposes. */
+  pub mtime_secs: i64,
+  pub mtime_nanos: u32,
+  pub ctime_secs: i64,
+  pub ctime_nanos: u32,
+}
+
+impl FileMetadata {
+  fn from_thrift(metadata: &pants_vcfs_interface::PosixFileMetadata) -> Self {
+    match metadata {
+      pants_vcfs_interface::PosixFileMetadata {
+        mode: Some(mode),
+        size_bytes: Some(size_bytes),
+        mtime_secs: Some(mtime_secs),
+        mtime_nanos: Some(mtime_nanos),
+        ctime_secs: Some(ctime_secs),
+        ctime_nanos: Some(ctime_nanos),
+      } => FileMetadata {
+        /* The owner, group, and other execute bits are 0o100, 0o010, and 0o001 respectively. */
+        is_executable: (*mode & 0o111) != 0,
+        size_bytes: *size_bytes as u64,
+        mtime_secs: *mtime_secs,
+        mtime_nanos: *mtime_nanos as u32,
+        ctime_secs: *ctime_secs,
+        ctime_nanos: *ctime_nanos as u32,
+      },
+      x => unimplemented!("could not parse posix file metadata struct {:?}", x),
     }
   }
 }
@@ -70,24 +148,47 @@ impl DerefMut for ShmHandle {
 pub struct FileWithContents<'a> {
   pub path: &'a Path,
   pub contents: &'a [u8],
+  pub metadata: FileMetadata,
 }
 
 impl<'a> FileWithContents<'a> {
   pub fn from_shm_descriptor(
     bytes: &'a [u8],
     fd: &'a pants_vcfs_interface::FileWithContentsDescriptor,
-  ) -> Self {
+  ) -> Result<Self, ShmError> {
     match fd {
       pants_vcfs_interface::FileWithContentsDescriptor {
         path: Some(path),
         contents_start: Some(contents_start),
         contents_end: Some(contents_end),
+        metadata: Some(metadata),
         ..
       } => {
-        assert!(contents_end >= contents_start);
+        let (contents_start, contents_end) = (*contents_start as usize, *contents_end as usize);
+        if contents_start > contents_end {
+          return Err(ShmError::S(format!(
+            "file with contents descriptor for {:?} had contents_start ({}) > contents_end ({})",
+            path, contents_start, contents_end
+          )));
+        }
+        if contents_end > bytes.len() {
+          return Err(ShmError::S(format!(
+            "file with contents descriptor for {:?} pointed at [{}, {}), which is outside of its \
+             {}-byte shm segment",
+            path,
+            contents_start,
+            contents_end,
+            bytes.len()
+          )));
+        }
         let path = Path::new(path);
-        let contents = &bytes[(*contents_start as usize)..(*contents_end as usize)];
-        FileWithContents { path, contents }
+        let contents = &bytes[contents_start..contents_end];
+        let metadata = FileMetadata::from_thrift(metadata);
+        Ok(FileWithContents {
+          path,
+          contents,
+          metadata,
+        })
       }
       x => unimplemented!(
         "could not parse file with contents descriptor struct {:?}",
@@ -98,43 +199,66 @@ impl<'a> FileWithContents<'a> {
 }
 
 impl ShmHandle {
-  pub fn new(key: key_t, size_bytes: usize, permission: Permission) -> Result<Self, ShmError> {
-    let fd_perm = match permission {
-      Permission::Read => IPC_R,
-      Permission::Write => IPC_R | IPC_W,
-    };
+  ///
+  /// Opens (and, for `Permission::Write`, creates and sizes) a POSIX shared memory object named
+  /// `name`. `name` should begin with a `/` and contain no other `/`s, per `shm_open(3)`.
+  ///
+  pub fn new(name: &str, size_bytes: usize, permission: Permission) -> Result<Self, ShmError> {
+    let c_name = CString::new(name)?;
 
-    let shm_fd = unsafe {
-      let fd = mmap_bindings::shmget(
-        key,
-        size_bytes as size_t,
-        (IPC_CREAT | fd_perm) as os::raw::c_int,
-      );
-      if fd == -1 {
-        let err = io::Error::last_os_error();
-        return Err(ShmError::S(format!("failed to open SHM: {:?}", err)));
-      }
-      fd
+    let (oflag, mode): (libc::c_int, mode_t) = match permission {
+      Permission::Read => (libc::O_RDONLY, 0o444),
+      Permission::Write => (libc::O_RDWR | libc::O_CREAT, 0o644),
     };
 
-    let shmat_prot = match permission {
-      Permission::Read => SHM_RDONLY,
-      Permission::Write => 0,
-    };
-    let mmap_addr = unsafe {
-      let addr = mmap_bindings::shmat(shm_fd, ptr::null(), shmat_prot as os::raw::c_int);
-      #[allow(non_snake_case)]
-      let MAP_FAILED = mem::transmute::<i64, *mut os::raw::c_void>(-1);
-      if addr == MAP_FAILED {
+    let shm_fd = unsafe { libc::shm_open(c_name.as_ptr(), oflag, libc::c_uint::from(mode)) };
+    if shm_fd == -1 {
+      let err = io::Error::last_os_error();
+      return Err(ShmError::S(format!(
+        "failed to shm_open {:?}: {:?}",
+        name, err
+      )));
+    }
+
+    if let Permission::Write = permission {
+      if unsafe { libc::ftruncate(shm_fd, size_bytes as off_t) } == -1 {
         let err = io::Error::last_os_error();
+        unsafe {
+          libc::close(shm_fd);
+        }
         return Err(ShmError::S(format!(
-          "failed to mmap SHM at fd {:?}: {:?}",
-          shm_fd, err
+          "failed to ftruncate shm {:?} to {} bytes: {:?}",
+          name, size_bytes, err
         )));
       }
-      addr
+    }
+
+    let prot = match permission {
+      Permission::Read => libc::PROT_READ,
+      Permission::Write => libc::PROT_READ | libc::PROT_WRITE,
     };
 
+    let mmap_addr = unsafe {
+      libc::mmap(
+        ptr::null_mut(),
+        size_bytes as size_t,
+        prot,
+        libc::MAP_SHARED,
+        shm_fd,
+        0,
+      )
+    };
+    if mmap_addr == libc::MAP_FAILED {
+      let err = io::Error::last_os_error();
+      unsafe {
+        libc::close(shm_fd);
+      }
+      return Err(ShmError::S(format!(
+        "failed to mmap shm {:?} (fd {:?}): {:?}",
+        name, shm_fd, err
+      )));
+    }
+
     Ok(ShmHandle {
       size_bytes,
       shm_fd,
@@ -146,10 +270,15 @@ impl ShmHandle {
 
 impl Drop for ShmHandle {
   fn drop(&mut self) {
-    let rc = unsafe { mmap_bindings::shmdt(self.mmap_addr) };
+    let rc = unsafe { libc::munmap(self.mmap_addr, self.size_bytes) };
     if rc == -1 {
-      let err = io::Error::last_os_error();
-      panic!("error dropping shm mapping: {:?}", err);
+      panic!(
+        "error unmapping shm region: {:?}",
+        io::Error::last_os_error()
+      );
+    }
+    unsafe {
+      libc::close(self.shm_fd);
     }
   }
 }